@@ -0,0 +1,95 @@
+use chrono::{TimeZone, Utc};
+use js_sys::Date;
+use state::save::SaveFile;
+use state::Game;
+use web_sys::Storage;
+
+/// How many snapshots are kept for each room.
+const MAX_SNAPSHOTS: usize = 10;
+
+/// How long to wait after a snapshot before taking the next one.
+const INTERVAL_MS: f64 = 2.0 * 60.0 * 1000.0;
+
+fn storage() -> Option<Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+/// Copies of a room's game kept in localStorage, newest last, so that the
+/// game can be put back if the server loses it.
+pub struct Autosave {
+    room: String,
+    snapshots: Vec<SaveFile>,
+    last_taken: f64,
+}
+
+impl Autosave {
+    pub fn load(room: &str) -> Self {
+        let snapshots = storage()
+            .and_then(|s| s.get_item(&key(room)).ok().flatten())
+            .and_then(|json| serde_json::from_str::<Vec<serde_json::Value>>(&json).ok())
+            .unwrap_or_default()
+            .into_iter()
+            // Old snapshots are migrated like any other save
+            .filter_map(|v| SaveFile::from_slice(v.to_string().as_bytes()).ok())
+            .collect();
+        Self {
+            room: room.to_string(),
+            snapshots,
+            last_taken: 0.0,
+        }
+    }
+
+    pub fn snapshots(&self) -> &[SaveFile] {
+        &self.snapshots
+    }
+
+    /// Takes a snapshot of `game` if enough time has passed and it changed.
+    /// An empty game is never kept, so that a server that lost the room
+    /// doesn't push out the copies of it. Returns true if it took one.
+    pub fn record(&mut self, game: &Game) -> bool {
+        let now = Date::now();
+        if now - self.last_taken < INTERVAL_MS || is_empty(game) {
+            return false;
+        }
+        let save = SaveFile::new(
+            Some(self.room.clone()),
+            Utc.timestamp_millis(now as i64),
+            game,
+        );
+        if self.snapshots.last().map(|s| &s.game) == Some(&save.game) {
+            return false;
+        }
+        self.last_taken = now;
+        self.snapshots.push(save);
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.remove(0);
+        }
+        self.store();
+        true
+    }
+
+    /// Drops the oldest snapshots until they fit in the browser's quota.
+    fn store(&mut self) {
+        let storage = match storage() {
+            Some(storage) => storage,
+            None => return,
+        };
+        while !self.snapshots.is_empty() {
+            let json = serde_json::to_string(&self.snapshots).unwrap();
+            if storage.set_item(&key(&self.room), &json).is_ok() {
+                return;
+            }
+            self.snapshots.remove(0);
+        }
+        storage.remove_item(&key(&self.room)).ok();
+    }
+}
+
+fn key(room: &str) -> String {
+    format!("roll4_autosave_{}", room)
+}
+
+/// True for a game nobody has done anything in yet.
+pub fn is_empty(game: &Game) -> bool {
+    game.characters.iter().next().is_none() && game.log.is_empty()
+}
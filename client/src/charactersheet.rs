@@ -1,6 +1,11 @@
+use crate::textinput::TextInput;
 use aper::data_structures::ListItem;
 use aper::StateMachine;
+use state::character::Effect;
+use state::list::move_item;
+use state::text::Text;
 use state::Character;
+use uuid::Uuid;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use web_sys::{Document, HtmlObjectElement, SvgElement};
@@ -24,6 +29,10 @@ pub enum Message {
     MemoryClicked(u8),
     BodyClicked(u8),
     MindClicked(u8),
+    GravityClicked(i8),
+    MoveEffect(Uuid, usize),
+    MoveNote(Uuid, usize),
+    DeleteNote(Uuid),
 }
 use Message::*;
 
@@ -68,6 +77,26 @@ impl Component for CharacterSheet {
                 .props
                 .cb
                 .emit(self.props.character.map_mind(|m| m.replace(x))),
+            GravityClicked(x) => self
+                .props
+                .cb
+                .emit(self.props.character.map_preferred_gravity(|g| g.replace(x))),
+            MoveEffect(id, to) => {
+                let character = &self.props.character;
+                if let Some(t) = move_item(&character.effects, id, to) {
+                    self.props.cb.emit(character.map_effects(|_| t));
+                }
+            }
+            MoveNote(id, to) => {
+                let character = &self.props.character;
+                if let Some(t) = move_item(&character.notes, id, to) {
+                    self.props.cb.emit(character.map_notes(|_| t));
+                }
+            }
+            DeleteNote(id) => self
+                .props
+                .cb
+                .emit(self.props.character.map_notes(|n| n.delete(id))),
         }
         false
     }
@@ -86,46 +115,141 @@ impl Component for CharacterSheet {
         let character = &self.props.character;
         html! { <div class="charactersheet">
         <object ref=self.svg_doc.clone() onload=self.link.callback(|_| SvgLoaded)
-                type="image/svg+xml" data="client/sheet.svg" id="svg"></object>
-        <input type="text" class="name" value=character.name.value()
-                 onchange=self.props.cb.reform({
+                type="image/svg+xml" data="/client/sheet.svg" id="svg"></object>
+        <TextInput class="name" text=character.name.clone()
+                 cb=self.props.cb.reform({
                      let character = character.clone();
-                     move |i: ChangeData| match i {
-                         ChangeData::Value(v) => character.map_name(|n| n.replace(v)),
-                         _ => unreachable!()
-                     }
+                     move |t| character.map_name(|_| t)
                  })/>
-        <input type="text" class="habitat" value=character.habitat.value()
-                 onchange=self.props.cb.reform({
+        <TextInput class="habitat" text=character.habitat.clone()
+                 cb=self.props.cb.reform({
                      let character = character.clone();
-                     move |i: ChangeData| match i {
-                         ChangeData::Value(v) => character.map_habitat(|n| n.replace(v)),
-                         _ => unreachable!()
-                     }
+                     move |t| character.map_habitat(|_| t)
                  })/>
-        {for character.notes.iter().enumerate().map(|(i, ListItem{id, value, ..})|{
-            html!{
-                <input type="text" class=format!("note_{}", i) value=value.value()
-                    onchange=self.props.cb.reform({
+        <div class="notes">
+            {for character.notes.iter().enumerate().map(|(i, item)| self.view_note(i, item))}
+            <input type="text" class="note" placeholder="+"
+                onchange=self.props.cb.reform({
+                    let character = character.clone();
+                    move |i: ChangeData| match i {
+                        ChangeData::Value(v) => character.map_notes(|n| n.append(Text::new(&v)).1),
+                        _ => unreachable!()
+                    }
+                })/>
+        </div>
+        {for (0..EFFECT_SLOTS).map(|i| self.view_effect(i))}
+        </div> }
+    }
+}
+
+impl CharacterSheet {
+    /// Typing into the last, empty line adds a note and leaving a note
+    /// empty removes it, as does Backspace on an empty one. Alt+Up and Alt+Down
+    /// move a note.
+    fn view_note(&self, i: usize, item: ListItem<Text>) -> Html {
+        let character = &self.props.character;
+        let id = item.id;
+        let empty = item.value.is_empty();
+
+        let onkeydown = self.link.batch_callback(move |e: KeyboardEvent| {
+            match (e.key().as_str(), e.alt_key()) {
+                ("ArrowUp", true) if i > 0 => vec![MoveNote(id, i - 1)],
+                ("ArrowDown", true) => vec![MoveNote(id, i + 2)],
+                ("Backspace", false) if empty => vec![DeleteNote(id)],
+                _ => vec![],
+            }
+        });
+        let cb = self.props.cb.reform({
+            let character = character.clone();
+            move |t| character.map_notes(move |n| n.map_item(id, |_| t))
+        });
+        let onchange = self.link.batch_callback(move |v: String| {
+            if v.is_empty() {
+                vec![DeleteNote(id)]
+            } else {
+                vec![]
+            }
+        });
+
+        html! {
+            <TextInput key=id.to_string() class="note" text=item.value.clone() cb=cb onchange=onchange onkeydown=onkeydown/>
+        }
+    }
+
+    /// Typing into an empty slot adds an effect and clearing both fields
+    /// of an effect removes it. Alt+Up and Alt+Down move an effect.
+    fn view_effect(&self, i: usize) -> Html {
+        let character = &self.props.character;
+        let effect = character.effects.iter().nth(i);
+
+        let (id, name, value) = match effect {
+            Some(ListItem { id, value, .. }) => {
+                (id, value.name.value().clone(), value.value.value().clone())
+            }
+            None => {
+                let add = |make: fn(String) -> Effect| {
+                    self.props.cb.reform({
                         let character = character.clone();
                         move |i: ChangeData| match i {
-                            ChangeData::Value(v) => character.map_notes(move |n| n.map_item(id, |i| i.replace(v))),
-                            _ => unreachable!()
+                            ChangeData::Value(v) => {
+                                character.map_effects(|e| e.append(make(v)).1)
+                            }
+                            _ => unreachable!(),
                         }
-                    })/>
+                    })
+                };
+                return html! {<>
+                    <input type="text" class=format!("effect_value_{}", i)
+                        onchange=add(|v| Effect::new("".to_string(), v))/>
+                    <input type="text" class=format!("effect_name_{}", i)
+                        onchange=add(|v| Effect::new(v, "".to_string()))/>
+                </>};
             }
-        })}
-        {for (0..18).map(|i| {
-            html!{<>
-                <input type="text" class=format!("effect_value_{}", i)/>
-                <input type="text" class=format!("effect_name_{}", i)/>
-            </>}
-        })}
-        </div> }
+        };
+
+        let onkeydown = self.link.batch_callback(move |e: KeyboardEvent| {
+            if !e.alt_key() {
+                return vec![];
+            }
+            match e.key().as_str() {
+                "ArrowUp" if i > 0 => vec![MoveEffect(id, i - 1)],
+                "ArrowDown" => vec![MoveEffect(id, i + 2)],
+                _ => vec![],
+            }
+        });
+        let value_cb = self.props.cb.reform({
+            let character = character.clone();
+            let name = name.clone();
+            move |i: ChangeData| match i {
+                ChangeData::Value(v) if v.is_empty() && name.is_empty() => {
+                    character.map_effects(|e| e.delete(id))
+                }
+                ChangeData::Value(v) => character
+                    .map_effects(|e| e.map_item(id, |e| e.map_value(|x| x.replace(v)))),
+                _ => unreachable!(),
+            }
+        });
+        let name_cb = self.props.cb.reform({
+            let character = character.clone();
+            let value = value.clone();
+            move |i: ChangeData| match i {
+                ChangeData::Value(v) if v.is_empty() && value.is_empty() => {
+                    character.map_effects(|e| e.delete(id))
+                }
+                ChangeData::Value(v) => character
+                    .map_effects(|e| e.map_item(id, |e| e.map_name(|x| x.replace(v)))),
+                _ => unreachable!(),
+            }
+        });
+
+        html! {<>
+            <input type="text" class=format!("effect_value_{}", i) value=value
+                onchange=value_cb onkeydown=onkeydown.clone()/>
+            <input type="text" class=format!("effect_name_{}", i) value=name
+                onchange=name_cb onkeydown=onkeydown/>
+        </>}
     }
-}
 
-impl CharacterSheet {
     fn init_svg(&self) {
         let doc = self
             .svg_doc
@@ -195,6 +319,19 @@ impl CharacterSheet {
                 c.forget();
             }
         }
+
+        for i in -5..=5 {
+            let link = self.link.clone();
+            let c = Closure::wrap(
+                Box::new(move || link.send_message(GravityClicked(i))) as Box<dyn Fn()>
+            );
+            get_gravity(&doc, i)
+                .unchecked_into::<SvgElement>()
+                .set_onclick(Some(c.as_ref().unchecked_ref()));
+
+            // TODO don't do this, it leaks memory when characters are deleted
+            c.forget();
+        }
     }
 
     fn update_svg(&self) {
@@ -234,8 +371,9 @@ impl CharacterSheet {
                 set_mp(i, false);
             }
 
+            let gravity = *character.preferred_gravity.value();
             for i in -5..=5 {
-                set_highlight(get_gravity(&doc, i), false);
+                set_highlight(get_gravity(&doc, i), i == gravity);
             }
 
             let body = *character.body.value();
@@ -251,6 +389,9 @@ impl CharacterSheet {
     }
 }
 
+/// The sheet has room for this many effects.
+const EFFECT_SLOTS: usize = 18;
+
 static BODY_DESCRIPTIONS: &[&str] = &["wounded", "beaten", "ok"];
 static MIND_DESCRIPTIONS: &[&str] = &["shaken", "stressed", "ok"];
 
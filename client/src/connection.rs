@@ -0,0 +1,53 @@
+use state::undo::{snapshot, Step};
+use state::{Game, GameTransition};
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+/// How the client is doing at reaching the server, as shown in the room.
+#[derive(Clone, PartialEq)]
+pub enum Connection {
+    Online,
+    /// Changes are made to a local copy of the game until the server is
+    /// heard from again.
+    Offline { queued: usize },
+    /// Back online, but these characters were changed by someone else
+    /// while this player was changing them too.
+    Conflict { characters: Vec<String> },
+}
+
+/// The game as it was when the connection dropped, the local copy changed
+/// since, and the transitions that made those changes.
+pub struct Offline {
+    pub base: Game,
+    pub local: Game,
+    pub queue: Vec<GameTransition>,
+}
+
+impl Offline {
+    pub fn new(game: &Game) -> Self {
+        Self {
+            base: game.clone(),
+            local: game.clone(),
+            queue: Vec::new(),
+        }
+    }
+
+    /// Characters changed both here and on the server since the
+    /// connection dropped.
+    pub fn conflicts(&self, server: &Game) -> Vec<Uuid> {
+        let mine = changed(&self.base, &self.local);
+        changed(&self.base, server)
+            .intersection(&mine)
+            .copied()
+            .collect()
+    }
+}
+
+fn changed(before: &Game, after: &Game) -> BTreeSet<Uuid> {
+    Step::between(
+        &snapshot(&before.characters, &before.owners),
+        &snapshot(&after.characters, &after.owners),
+    )
+    .map(|step| step.characters().collect())
+    .unwrap_or_default()
+}
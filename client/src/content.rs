@@ -1,15 +1,39 @@
 use crate::charactersheet::CharacterSheet;
+use crate::connection::Connection;
 use crate::dicecomponent::DiceComponent;
-use crate::save::SaveButton;
+use crate::gmpanel::GmPanel;
+use crate::journal::JournalPanel;
+use crate::rollhistory::RollHistory;
+use crate::save::{ExportButton, SaveButton};
 use aper::data_structures::{ListItem, ListOperation};
+use state::dice::Roller;
+use state::expression::DiceExpression;
+use state::list::move_item;
+use state::player::{Player, PlayerKey, SharedKey};
 use state::Character;
 use state::{Game, GameTransition};
 use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::HtmlElement;
 use yew::prelude::*;
 
 pub struct Content {
+    room: String,
     state: Game,
+    players: Vec<Player>,
+    me: Option<(String, PlayerKey)>,
+    shared_key: Option<SharedKey>,
+    connection: Connection,
+    resolve: Callback<bool>,
+    hidden_roll: bool,
     character: Option<Uuid>,
+    expression: String,
+    expression_error: Option<String>,
+    confirm_delete: Option<Uuid>,
+    dragging: Option<Uuid>,
+    /// Listens for the undo and redo shortcuts anywhere on the page.
+    keydown: Closure<dyn Fn(KeyboardEvent)>,
     link: ComponentLink<Self>,
     cb: Callback<Option<GameTransition>>,
 }
@@ -17,12 +41,34 @@ pub struct Content {
 pub enum ContentMsg {
     SelectCharacter(Uuid),
     AddCharacter,
+    SetExpression(String),
+    SetHiddenRoll(bool),
+    RollExpression,
+    SetArchived(Uuid, bool),
+    AskDelete(Uuid),
+    Delete(Uuid),
+    CancelDelete,
+    DragStart(Uuid),
+    /// The dragged tab was dropped on the tab at this position.
+    DropAt(usize),
+    Undo,
+    Redo,
 }
 use ContentMsg::*;
 
 #[derive(Properties, Clone)]
 pub struct ContentProps {
+    pub room: String,
     pub state: Game,
+    pub players: Vec<Player>,
+    /// Name and key of this browser's player, once they have given a name.
+    pub me: Option<(String, PlayerKey)>,
+    /// The key this browser shares with the server, which opens hidden
+    /// rolls made for this player and hides the GM password on its way.
+    pub shared_key: Option<SharedKey>,
+    pub connection: Connection,
+    /// Whether to send changes held back by a conflict anyway.
+    pub resolve: Callback<bool>,
     pub cb: Callback<Option<GameTransition>>,
 }
 
@@ -32,9 +78,47 @@ impl Component for Content {
     type Properties = ContentProps;
 
     fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        let keydown = {
+            let link = link.clone();
+            Closure::wrap(Box::new(move |e: KeyboardEvent| {
+                // Text fields have their own undo
+                let tag = e
+                    .target()
+                    .and_then(|t| t.dyn_into::<HtmlElement>().ok())
+                    .map(|t| t.tag_name());
+                if matches!(tag.as_deref(), Some("INPUT") | Some("TEXTAREA"))
+                    || !(e.ctrl_key() || e.meta_key())
+                {
+                    return;
+                }
+                match (e.key().to_lowercase().as_str(), e.shift_key()) {
+                    ("z", false) => link.send_message(Undo),
+                    ("z", true) | ("y", false) => link.send_message(Redo),
+                    _ => return,
+                }
+                e.prevent_default();
+            }) as Box<dyn Fn(KeyboardEvent)>)
+        };
+        web_sys::window()
+            .unwrap()
+            .add_event_listener_with_callback("keydown", keydown.as_ref().unchecked_ref())
+            .unwrap();
+
         Self {
+            room: props.room,
             state: props.state,
+            players: props.players,
+            me: props.me,
+            shared_key: props.shared_key,
+            connection: props.connection,
+            resolve: props.resolve,
+            hidden_roll: false,
             character: None,
+            expression: "".to_string(),
+            expression_error: None,
+            confirm_delete: None,
+            dragging: None,
+            keydown,
             link,
             cb: props.cb,
         }
@@ -48,11 +132,72 @@ impl Component for Content {
                 self.character = Some(id);
                 self.cb.emit(Some(GameTransition::CharacterTransition(t)));
             }
+            SetExpression(e) => {
+                self.expression = e;
+                self.expression_error = None;
+            }
+            SetHiddenRoll(hidden) => self.hidden_roll = hidden,
+            RollExpression => match self.expression.parse::<DiceExpression>() {
+                Ok(e) if self.hidden_roll && self.is_gm() => {
+                    self.cb.emit(Some(GameTransition::HiddenRoll(e)))
+                }
+                Ok(e) => self.cb.emit(Some(GameTransition::Roll(e, self.roller()))),
+                Err(e) => self.expression_error = Some(e.to_string()),
+            },
+            SetArchived(id, archived) => {
+                if archived && self.character == Some(id) {
+                    self.character = None;
+                }
+                let t = self
+                    .state
+                    .characters
+                    .map_item(id, |c| c.map_archived(|a| a.replace(archived)));
+                self.cb.emit(Some(GameTransition::CharacterTransition(t)));
+            }
+            AskDelete(id) => self.confirm_delete = Some(id),
+            Delete(id) => {
+                self.confirm_delete = None;
+                if self.character == Some(id) {
+                    self.character = None;
+                }
+                let t = self.state.characters.delete(id);
+                self.cb.emit(Some(GameTransition::CharacterTransition(t)));
+            }
+            CancelDelete => self.confirm_delete = None,
+            DragStart(id) => self.dragging = Some(id),
+            DropAt(target) => {
+                let characters = &self.state.characters;
+                // The dragged tab takes the target's place, so coming from
+                // the left it goes after it
+                let t = self.dragging.take().and_then(|id| {
+                    let from = characters.iter().position(|c| c.id == id)?;
+                    let to = if from < target { target + 1 } else { target };
+                    move_item(characters, id, to)
+                });
+                if let Some(t) = t {
+                    self.cb.emit(Some(GameTransition::CharacterTransition(t)));
+                }
+            }
+            Undo => self.cb.emit(Some(GameTransition::Undo)),
+            Redo => self.cb.emit(Some(GameTransition::Redo)),
         }
         true
     }
 
+    fn destroy(&mut self) {
+        web_sys::window()
+            .unwrap()
+            .remove_event_listener_with_callback("keydown", self.keydown.as_ref().unchecked_ref())
+            .unwrap();
+    }
+
     fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        self.players = props.players;
+        self.me = props.me;
+        self.shared_key = props.shared_key;
+        self.resolve = props.resolve;
+        let connection_changed = self.connection != props.connection;
+        self.connection = props.connection;
         (if self.state != props.state {
             self.state = props.state;
             true
@@ -65,15 +210,42 @@ impl Component for Content {
             } else {
                 false
             }
-        })
+        }) || connection_changed
     }
 
     fn view(&self) -> Html {
         let roll_buttons = (1..=6).map(move |n| {
+            let expression: DiceExpression = format!("{}d4", n).parse().unwrap();
+            let roller = self.roller();
             html! {
-                <button onclick=self.cb.reform(move |_| Some(GameTransition::Roll(n)))>{n}</button>
+                <button onclick=self.cb.reform(move |_| Some(GameTransition::Roll(expression.clone(), roller.clone())))>{n}</button>
             }
         });
+        let expression_input = html! {<div>
+            <input type="text" placeholder="3d4+2" value=&self.expression
+                oninput=self.link.callback(|e: InputData| SetExpression(e.value))
+                onkeypress=self.link.batch_callback(|e: KeyboardEvent| {
+                    if e.key() == "Enter" { vec![RollExpression] } else { vec![] }
+                })/>
+            <button onclick=self.link.callback(|_| RollExpression)>{"Roll"}</button>
+            {if self.is_gm() {
+                html!{<label>
+                    <input type="checkbox" checked=self.hidden_roll
+                        onclick=self.link.callback({
+                            let hidden = self.hidden_roll;
+                            move |_| SetHiddenRoll(!hidden)
+                        })/>
+                    {"Hidden"}
+                </label>}
+            } else {
+                html!{}
+            }}
+            {if let Some(error) = &self.expression_error {
+                html!{<div class="error">{error}</div>}
+            } else {
+                html!{}
+            }}
+        </div>};
         let dice = &self.state.dice;
 
         // TODO allow selecting your character
@@ -101,34 +273,177 @@ impl Component for Content {
                     id, f,
                 )))
             });
-            html! {<CharacterSheet character=character.value cb=cb />}
+            let owner = self.state.owners.get(&id).map(|key| {
+                let name = self.players.iter().find(|p| p.key == *key)
+                    .map_or("someone who is not here", |p| p.name.as_str());
+                let read_only = if self.state.may_edit(self.me.as_ref().map(|(_, k)| *k), id) {
+                    ""
+                } else {
+                    " (read only)"
+                };
+                html! {<div class="owner">{format!("Owned by {}{}", name, read_only)}</div>}
+            });
+            let actions = if self.confirm_delete == Some(id) {
+                html! {<div class="characteractions">
+                    {format!("Delete {} for everyone? ", character.value.name)}
+                    <button onclick=self.link.callback(move |_| Delete(id))>{"Delete"}</button>
+                    <button onclick=self.link.callback(|_| CancelDelete)>{"Cancel"}</button>
+                </div>}
+            } else {
+                html! {<div class="characteractions">
+                    <button onclick=self.link.callback(move |_| SetArchived(id, true))>{"Archive"}</button>
+                    <button onclick=self.link.callback(move |_| AskDelete(id))>{"Delete"}</button>
+                    <ExportButton room=self.room.clone() characters=self.state.characters.clone() character=id />
+                </div>}
+            };
+            html! {<>
+                {owner.unwrap_or_else(|| html! {})}
+                {actions}
+                <div class="sheetandjournal">
+                    <CharacterSheet character=character.value cb=cb.clone() />
+                    <JournalPanel character=character.value cb=cb />
+                </div>
+            </>}
         } else {
             html! {}
         };
 
-        let tabs = self.state.characters.iter().map(|ListItem{value, id, ..}| {
+        // Positions count archived characters too, as they are still in the list
+        let tabs = self.state.characters.iter().enumerate()
+            .filter(|(_, ListItem{value, ..})| !*value.archived.value())
+            .map(|(i, ListItem{value, id, ..})| {
             let class = if Some(id) == self.character {"selected"} else {""};
             html! {
-                <span class=class onclick=self.link.callback(move |_| SelectCharacter(id))>{value.name.value()}</span>
+                <span class=class draggable="true"
+                    onclick=self.link.callback(move |_| SelectCharacter(id))
+                    ondragstart=self.link.callback(move |_: DragEvent| DragStart(id))
+                    ondragover=Callback::from(|e: DragEvent| e.prevent_default())
+                    ondrop=self.link.callback(move |e: DragEvent| {
+                        e.prevent_default();
+                        DropAt(i)
+                    })>
+                    {value.name.to_string()}
+                </span>
             }
         });
 
+        let archived: Vec<Html> = self.state.characters.iter()
+            .filter(|ListItem{value, ..}| *value.archived.value())
+            .map(|ListItem{value, id, ..}| html! {
+                <li>
+                    {value.name.to_string()}{" "}
+                    <button onclick=self.link.callback(move |_| SetArchived(id, false))>{"Restore"}</button>
+                </li>
+            })
+            .collect();
+        let archive = if archived.is_empty() {
+            html! {}
+        } else {
+            html! {<details id="archive">
+                <summary>{format!("Archived characters ({})", archived.len())}</summary>
+                <ul>{for archived}</ul>
+            </details>}
+        };
+
         html! {<div id="main">
             <div id="characters">
                <div id="tabs">
                {for tabs}
                {add_char_button}
                </div>
+               {self.view_undo()}
+               {archive}
                {character_sheet}
             </div>
 
             <div id="roller">
                <div>{"Roll: "}{for roll_buttons}</div>
-               <DiceComponent roll_id=dice.roll_id rolls=dice.rolls.clone() last_rolled=dice.last_rolled.clone()
-                 reroll_cb=reroll />
+               {expression_input}
+               <div id="dicearea">
+                 <div>
+                   <DiceComponent roll_id=dice.roll_id rolls=dice.rolls() total=dice.result.as_ref().map(|r| r.total())
+                     roller=dice.roller.as_ref().map(|r| r.name(&self.state.characters))
+                     last_rolled=dice.last_rolled.clone() reroll_cb=reroll />
+                 </div>
+                 <RollHistory log=self.state.log.clone() characters=self.state.characters.clone()
+                   hidden_key=self.shared_key cb=self.cb.clone() />
+               </div>
             </div>
 
-            <SaveButton characters=self.state.characters.clone() load=self.cb.reform(|x| Some(GameTransition::Load(x))) />
+            <div id="savewidget">
+               {self.view_connection()}
+               <GmPanel gm=self.state.gm kicked=self.state.kicked.clone()
+                 members=self.state.members.clone() locked=self.state.locked
+                 players=self.players.clone() me=self.me.as_ref().map(|(_, k)| *k)
+                 shared_key=self.shared_key cb=self.cb.clone() />
+               <SaveButton room=self.room.clone() game=self.state.clone()
+                 restore=self.cb.reform(|x| Some(GameTransition::Restore(x)))
+                 load=self.cb.reform(|x| Some(GameTransition::Load(x)))
+                 import=self.cb.reform(|x| Some(GameTransition::Import(x)))
+                 can_load=self.state.is_gm(self.me.as_ref().map(|(_, k)| *k)) />
+            </div>
         </div>}
     }
 }
+
+impl Content {
+    fn view_connection(&self) -> Html {
+        match &self.connection {
+            Connection::Online => html! {<div id="connection" class="online">{"Connected"}</div>},
+            Connection::Offline { queued } => html! {<div id="connection" class="offline">
+                {format!("Offline. {} changes will be sent when the server is back.", queued)}
+            </div>},
+            Connection::Conflict { characters } => html! {<div id="connection" class="conflict">
+                {format!(
+                    "While you were offline, someone else also changed {}. Your changes have not been sent.",
+                    characters.join(", "),
+                )}
+                <button onclick=self.resolve.reform(|_| true)>{"Send mine anyway"}</button>
+                <button onclick=self.resolve.reform(|_| false)>{"Discard mine"}</button>
+            </div>},
+        }
+    }
+
+    fn view_undo(&self) -> Html {
+        let stack = match self.me.as_ref().and_then(|(_, k)| self.state.undo.get(k)) {
+            Some(stack) => stack,
+            None => return html! {},
+        };
+        let (characters, owners) = (&self.state.characters, &self.state.owners);
+        // A step somebody has changed since can only be dropped
+        let undo = match (stack.next_undo(), stack.undo_possible(characters, owners)) {
+            (None, _) => html! {},
+            (Some(_), true) => html! {
+                <button title="Ctrl+Z" onclick=self.link.callback(|_| Undo)>{"Undo"}</button>
+            },
+            (Some(_), false) => html! {
+                <button title="Changed by someone since" onclick=self.link.callback(|_| Undo)>
+                    {"Forget last change"}
+                </button>
+            },
+        };
+        let redo = match (stack.next_redo(), stack.redo_possible(characters, owners)) {
+            (Some(_), true) => html! {
+                <button title="Ctrl+Shift+Z" onclick=self.link.callback(|_| Redo)>{"Redo"}</button>
+            },
+            _ => html! {},
+        };
+        html! {<div class="characteractions">{undo}{redo}</div>}
+    }
+
+    /// True only if the room has a GM and it is this player.
+    fn is_gm(&self) -> bool {
+        let me = self.me.as_ref().map(|(_, k)| *k);
+        self.state.gm.is_some() && self.state.gm == me
+    }
+
+    /// Rolls are attributed to the selected character if this player may
+    /// roll for it, or else to the player. The server fills in the name.
+    fn roller(&self) -> Option<Roller> {
+        let me = self.me.as_ref().map(|(_, k)| *k);
+        self.character
+            .filter(|id| self.state.may_edit(me, *id))
+            .map(Roller::Character)
+            .or_else(|| self.me.as_ref().map(|(name, _)| Roller::Player(name.clone())))
+    }
+}
@@ -1,3 +1,4 @@
+use state::expression::Die as Rolled;
 use std::iter::repeat;
 use std::num::Wrapping;
 use yew::prelude::*;
@@ -6,19 +7,23 @@ pub struct DiceComponent {
     last_update: Wrapping<u8>,
     dice: Vec<Die>,
     selected: Vec<bool>,
+    total: Option<i32>,
+    roller: Option<String>,
     link: ComponentLink<Self>,
     reroll_cb: Option<Callback<Option<Vec<bool>>>>,
 }
 
 struct Die {
-    roll: u8,
+    roll: Rolled,
     class: bool,
 }
 
 #[derive(Properties, Clone)]
 pub struct DiceProps {
     pub roll_id: Wrapping<u8>,
-    pub rolls: Vec<u8>,
+    pub rolls: Vec<Rolled>,
+    pub total: Option<i32>,
+    pub roller: Option<String>,
     pub last_rolled: Vec<bool>,
     pub reroll_cb: Option<Callback<Option<Vec<bool>>>>,
 }
@@ -46,6 +51,8 @@ impl Component for DiceComponent {
             last_update: props.roll_id,
             dice,
             selected,
+            total: props.total,
+            roller: props.roller,
             link,
             reroll_cb: props.reroll_cb,
         }
@@ -68,6 +75,8 @@ impl Component for DiceComponent {
                 .map(|(r, c)| Die { roll: r, class: c })
                 .collect();
             self.selected = vec![false; self.dice.len()];
+            self.total = props.total;
+            self.roller = props.roller;
             true
         } else {
             false
@@ -105,15 +114,27 @@ impl Component for DiceComponent {
         };
 
         html! { <>
+            {if let Some(roller) = &self.roller {
+                html!{<div class="roller">{format!("Rolled by {}", roller)}</div>}
+            } else {
+                html!{}
+            }}
             <div class="diebox">
             {for self.dice.iter().enumerate().map(|(i, d)|
                 html!{<span data-selected=self.selected[i]
+                      data-kept=d.roll.kept data-exploded=d.roll.exploded
+                      title=format!("d{}", d.roll.sides)
                       class=if d.class {"die dieani1"} else {"die dieani2"}
                       onclick=self.link.callback(move |_| Select(i))>
-                      {d.roll}
+                      {d.roll.value}
                 </span>}
             )}
             </div>
+            {if let Some(total) = self.total {
+                html!{<div class="dietotal">{"Total: "}{total}</div>}
+            } else {
+                html!{}
+            }}
             <button disabled=cannot_reroll onclick=cb>{"Reroll"}</button>
         </>}
     }
@@ -0,0 +1,154 @@
+use state::player::{Player, PlayerKey, SharedKey};
+use state::sealed::SealedPassword;
+use state::GameTransition;
+use std::collections::BTreeSet;
+use uuid::Uuid;
+use yew::prelude::*;
+
+pub struct GmPanel {
+    props: Props,
+    password: String,
+    link: ComponentLink<Self>,
+}
+
+#[derive(Properties, Clone, PartialEq)]
+pub struct Props {
+    pub gm: Option<PlayerKey>,
+    pub kicked: BTreeSet<PlayerKey>,
+    pub members: BTreeSet<PlayerKey>,
+    pub locked: bool,
+    pub players: Vec<Player>,
+    pub me: Option<PlayerKey>,
+    /// Hides the password from everyone but the server.
+    pub shared_key: Option<SharedKey>,
+    pub cb: Callback<Option<GameTransition>>,
+}
+
+pub enum Msg {
+    SetPassword(String),
+    Claim,
+}
+
+impl Component for GmPanel {
+    type Message = Msg;
+    type Properties = Props;
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            props,
+            password: "".to_string(),
+            link,
+        }
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::SetPassword(p) => self.password = p,
+            Msg::Claim => {
+                let password = std::mem::take(&mut self.password);
+                if let Some(key) = &self.props.shared_key {
+                    let nonce = Uuid::new_v4().as_u128() as u64;
+                    let sealed = SealedPassword::seal(&password, key, nonce);
+                    self.props.cb.emit(Some(GameTransition::ClaimGm(sealed)));
+                }
+            }
+        }
+        true
+    }
+
+    fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        if self.props != props {
+            self.props = props;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn view(&self) -> Html {
+        let Props { gm, me, .. } = &self.props;
+        if gm.is_some() && gm == me {
+            return self.view_gm();
+        }
+
+        let status = match gm {
+            None => "Nobody is GM. ".to_string(),
+            Some(key) => format!("GM: {}. ", self.name(*key)),
+        };
+        let admitted = me.map_or(true, |me| self.admitted(me));
+        html! {<div id="gmpanel">
+            {status}
+            {if !admitted {
+                html! {"The GM has to let you in before you can change anything."}
+            } else if me.is_some() {
+                html! {<>
+                    <input type="password" placeholder="room password" value=&self.password
+                        oninput=self.link.callback(|e: InputData| Msg::SetPassword(e.value))/>
+                    <button onclick=self.link.callback(|_| Msg::Claim)>{"Become GM"}</button>
+                </>}
+            } else {
+                html! {"Give your name to become GM."}
+            }}
+        </div>}
+    }
+}
+
+impl GmPanel {
+    fn name(&self, key: PlayerKey) -> String {
+        self.props
+            .players
+            .iter()
+            .find(|p| p.key == key)
+            .map_or_else(|| "someone who is not here".to_string(), |p| p.name.clone())
+    }
+
+    /// Mirrors [`state::Game::admits`].
+    fn admitted(&self, key: PlayerKey) -> bool {
+        let Props {
+            gm,
+            kicked,
+            members,
+            locked,
+            ..
+        } = &self.props;
+        !kicked.contains(&key) && (!locked || members.contains(&key) || *gm == Some(key))
+    }
+
+    fn view_gm(&self) -> Html {
+        let cb = &self.props.cb;
+        let mut keys: Vec<PlayerKey> = self.props.players.iter().map(|p| p.key).collect();
+        keys.extend(self.props.kicked.iter().copied());
+        keys.sort();
+        keys.dedup();
+        keys.retain(|k| Some(*k) != self.props.me);
+
+        html! {<div id="gmpanel">
+            {"You are the GM. "}
+            {if self.props.locked {
+                "Since you kicked somebody, newcomers wait until you let them in. "
+            } else {
+                ""
+            }}
+            <button onclick=cb.reform(|_| Some(GameTransition::ReleaseGm))>{"Stop being GM"}</button>
+            <button onclick=cb.reform(|_| Some(GameTransition::ClearLog))>{"Clear roll log"}</button>
+            <ul>
+            {for keys.into_iter().map(|key| {
+                let kicked = self.props.kicked.contains(&key);
+                html! {<li>
+                    {self.name(key)}{" "}
+                    {if kicked {
+                        html! {<button onclick=cb.reform(move |_| Some(GameTransition::Unkick(key)))>{"Let back in"}</button>}
+                    } else if !self.admitted(key) {
+                        html! {<>
+                            <button onclick=cb.reform(move |_| Some(GameTransition::Unkick(key)))>{"Let in"}</button>
+                            <button onclick=cb.reform(move |_| Some(GameTransition::Kick(key)))>{"Kick"}</button>
+                        </>}
+                    } else {
+                        html! {<button onclick=cb.reform(move |_| Some(GameTransition::Kick(key)))>{"Kick"}</button>}
+                    }}
+                </li>}
+            })}
+            </ul>
+        </div>}
+    }
+}
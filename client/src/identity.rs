@@ -0,0 +1,48 @@
+use state::player::{PlayerKey, SecretKey};
+use web_sys::Storage;
+
+/// Who this browser's player is. Kept in localStorage so that it survives
+/// reloads; the secret key is what makes characters this player's own, and
+/// it never leaves the browser.
+#[derive(Clone, PartialEq)]
+pub struct Identity {
+    pub name: String,
+    pub secret: SecretKey,
+    pub key: PlayerKey,
+}
+
+const NAME_KEY: &str = "roll4_name";
+const SECRET_KEY: &str = "roll4_secret";
+
+fn storage() -> Option<Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+impl Identity {
+    /// Loads the identity, creating a secret key the first time.
+    pub fn load() -> Self {
+        let storage = storage();
+        let get = |key| storage.as_ref().and_then(|s| s.get_item(key).ok().flatten());
+
+        let name = get(NAME_KEY).unwrap_or_default();
+        let secret = match get(SECRET_KEY).and_then(|s| SecretKey::from_hex(&s)) {
+            Some(secret) => secret,
+            None => {
+                let secret = SecretKey::generate();
+                if let Some(s) = &storage {
+                    s.set_item(SECRET_KEY, &secret.to_hex()).ok();
+                }
+                secret
+            }
+        };
+        let key = PlayerKey::of(&secret.public());
+        Self { name, secret, key }
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        if let Some(s) = storage() {
+            s.set_item(NAME_KEY, &name).ok();
+        }
+        self.name = name;
+    }
+}
@@ -0,0 +1,224 @@
+use crate::textinput::TextInput;
+use aper::data_structures::{List, ListItem, ListOperation};
+use aper::StateMachine;
+use pulldown_cmark::{html::push_html, CowStr, Event, Parser, Tag};
+use state::list::move_item;
+use state::text::Text;
+use state::Character;
+use uuid::Uuid;
+use web_sys::HtmlTextAreaElement;
+use yew::prelude::*;
+use yew::virtual_dom::VNode;
+
+/// The character's journal, shown as Markdown next to the sheet.
+pub struct JournalPanel {
+    editing: bool,
+    new_paragraph: NodeRef,
+    props: Props,
+    link: ComponentLink<Self>,
+}
+
+#[derive(PartialEq, Clone, Properties)]
+pub struct Props {
+    pub character: Character,
+    pub cb: Callback<<Character as StateMachine>::Transition>,
+}
+
+pub enum Msg {
+    ToggleEditing,
+    /// Somebody stopped typing in a paragraph, which is split at its blank
+    /// lines or removed if it is blank.
+    Tidy(Uuid),
+    /// Text for a new paragraph at the end.
+    Add(String),
+}
+use Msg::*;
+
+impl Component for JournalPanel {
+    type Message = Msg;
+    type Properties = Props;
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            editing: false,
+            new_paragraph: NodeRef::default(),
+            props,
+            link,
+        }
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            ToggleEditing => {
+                self.editing = !self.editing;
+                true
+            }
+            Tidy(id) => {
+                let character = &self.props.character;
+                // Typing was sent as it happened, so the paragraph as last
+                // received is split rather than the field, which may be ahead
+                let text = match character.journal.iter().find(|p| p.id == id) {
+                    Some(paragraph) => paragraph.value.to_string(),
+                    None => return false,
+                };
+                for op in edit_paragraph(&character.journal, Some(id), &text) {
+                    self.props.cb.emit(character.map_journal(|_| op));
+                }
+                false
+            }
+            Add(text) => {
+                let character = &self.props.character;
+                for op in edit_paragraph(&character.journal, None, &text) {
+                    self.props.cb.emit(character.map_journal(|_| op));
+                }
+                if let Some(textarea) = self.new_paragraph.cast::<HtmlTextAreaElement>() {
+                    textarea.set_value("");
+                }
+                false
+            }
+        }
+    }
+
+    fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        if self.props != props {
+            self.props = props;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn view(&self) -> Html {
+        let journal = &self.props.character.journal;
+        let body = if self.editing {
+            let paragraphs = journal.iter().map(|ListItem { id, value, .. }| {
+                let cb = self.props.cb.reform({
+                    let character = self.props.character.clone();
+                    move |t| character.map_journal(move |j| j.map_item(id, |_| t))
+                });
+                html! {
+                    <TextInput key=id.to_string() multiline=true text=value.clone() cb=cb
+                        onchange=self.link.callback(move |_: String| Tidy(id))/>
+                }
+            });
+            html! {<>
+                {for paragraphs}
+                <textarea ref=self.new_paragraph.clone() placeholder="New paragraph"
+                    onchange=self.link.callback(|i: ChangeData| match i {
+                        ChangeData::Value(v) => Add(v),
+                        _ => unreachable!(),
+                    })/>
+            </>}
+        } else {
+            let text = journal
+                .iter()
+                .map(|p| p.value.to_string())
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            markdown(&text)
+        };
+
+        html! {<div class="journal">
+            <button onclick=self.link.callback(|_| ToggleEditing)>
+                {if self.editing { "Preview" } else { "Edit journal" }}
+            </button>
+            {body}
+        </div>}
+    }
+}
+
+/// Operations that put `text` in place of a paragraph. A blank line in the
+/// text splits it into more paragraphs, and a blank text removes it. The
+/// paragraph itself only gets the difference, so that someone typing in it
+/// at the same time keeps what they typed.
+fn edit_paragraph(journal: &List<Text>, id: Option<Uuid>, text: &str) -> Vec<ListOperation<Text>> {
+    type Op = ListOperation<Text>;
+    fn push(working: &mut List<Text>, ops: &mut Vec<Op>, op: Op) {
+        working.apply(op.clone());
+        ops.push(op);
+    }
+    let mut working = journal.clone();
+    let mut ops = Vec::new();
+
+    let mut parts = text
+        .split("\n\n")
+        .filter(|p| !p.trim().is_empty())
+        .map(str::to_string);
+    let mut previous = match (id, parts.next()) {
+        (Some(id), Some(first)) => {
+            let current = working.iter().find(|p| p.id == id).map(|p| p.value.to_string());
+            if current.as_ref() != Some(&first) {
+                let op = working.map_item(id, |p| p.edit(&first, Uuid::new_v4()));
+                push(&mut working, &mut ops, op);
+            }
+            Some(id)
+        }
+        (Some(id), None) => {
+            let op = working.delete(id);
+            push(&mut working, &mut ops, op);
+            return ops;
+        }
+        (None, Some(first)) => {
+            let (id, op) = working.append(Text::new(&first));
+            push(&mut working, &mut ops, op);
+            Some(id)
+        }
+        (None, None) => None,
+    };
+
+    for part in parts {
+        let (id, op) = working.append(Text::new(&part));
+        push(&mut working, &mut ops, op);
+        let after = previous.and_then(|p| working.iter().position(|i| i.id == p));
+        if let Some(op) = after.and_then(|i| move_item(&working, id, i + 1)) {
+            push(&mut working, &mut ops, op);
+        }
+        previous = Some(id);
+    }
+    ops
+}
+
+/// Renders Markdown. Raw HTML is shown as text and only ordinary links are
+/// kept, since anyone in the room can write here.
+fn markdown(text: &str) -> Html {
+    let safe = |url: CowStr<'static>| -> CowStr<'static> {
+        let lower = url.to_lowercase();
+        if lower.contains(':')
+            && !["http:", "https:", "mailto:"]
+                .iter()
+                .any(|s| lower.starts_with(s))
+        {
+            "#".into()
+        } else {
+            url
+        }
+    };
+    let events = Parser::new(text).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(kind, url, title)) => {
+            Event::Start(Tag::Link(kind, safe(url.into_static()), title))
+        }
+        Event::End(Tag::Link(kind, url, title)) => {
+            Event::End(Tag::Link(kind, safe(url.into_static()), title))
+        }
+        Event::Start(Tag::Image(kind, url, title)) => {
+            Event::Start(Tag::Image(kind, safe(url.into_static()), title))
+        }
+        Event::End(Tag::Image(kind, url, title)) => {
+            Event::End(Tag::Image(kind, safe(url.into_static()), title))
+        }
+        event => event,
+    });
+    let mut html = String::new();
+    push_html(&mut html, events);
+
+    let div = web_sys::window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .create_element("div")
+        .unwrap();
+    div.set_class_name("markdown");
+    div.set_inner_html(&html);
+    VNode::VRef(div.into())
+}
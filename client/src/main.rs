@@ -1,28 +1,44 @@
 #![recursion_limit = "1024"]
+mod autosave;
 mod charactersheet;
+mod connection;
 mod content;
 mod dicecomponent;
+mod gmpanel;
+mod identity;
+mod journal;
+mod rollhistory;
+mod room;
 mod save;
-use aper::StateMachineContainerProgram;
+mod textinput;
 use aper_yew::{ClientBuilder, View, ViewContext};
-use content::Content;
-use state::{Game, GameTransition};
+use room::{room_from_url, RoomGate};
+use state::{GameProgram, ProgramTransition};
 use yew::prelude::*;
 
 #[derive(Clone)]
-struct GameView;
+struct GameView {
+    room: String,
+}
 
 impl View for GameView {
-    type Callback = GameTransition;
-    type State = StateMachineContainerProgram<Game>;
+    type Callback = ProgramTransition;
+    type State = GameProgram;
 
     fn view(&self, state: &Self::State, context: &ViewContext<Self::Callback>) -> Html {
         html! {
-            <Content state=state.0.clone() cb=context.callback.clone() />
+            <RoomGate name=self.room.clone() game=state.room(&self.room).cloned()
+                players=state.players.iter().map(|(_, p)| p.clone()).collect::<Vec<_>>()
+                heartbeat=state.heartbeat server_key=state.server_key
+                unavailable=state.unavailable.contains(&self.room)
+                cb=context.callback.clone() />
         }
     }
 }
 
 fn main() {
-    ClientBuilder::new(GameView).mount_to_body();
+    ClientBuilder::new(GameView {
+        room: room_from_url(),
+    })
+    .mount_to_body();
 }
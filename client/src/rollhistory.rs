@@ -0,0 +1,106 @@
+use aper::data_structures::List;
+use js_sys::Date;
+use state::history::RollRecord;
+use state::player::SharedKey;
+use state::{Character, GameTransition};
+use wasm_bindgen::JsValue;
+use yew::prelude::*;
+
+pub struct RollHistory {
+    props: Props,
+}
+
+#[derive(Properties, Clone, PartialEq)]
+pub struct Props {
+    pub log: Vec<RollRecord>,
+    pub characters: List<Character>,
+    /// The key this browser shares with the server, which opens hidden
+    /// rolls made by this player.
+    pub hidden_key: Option<SharedKey>,
+    pub cb: Callback<Option<GameTransition>>,
+}
+
+impl Component for RollHistory {
+    type Message = ();
+    type Properties = Props;
+
+    fn create(props: Self::Properties, _: ComponentLink<Self>) -> Self {
+        Self { props }
+    }
+
+    fn update(&mut self, _: Self::Message) -> ShouldRender {
+        false
+    }
+
+    fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        if self.props != props {
+            self.props = props;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn view(&self) -> Html {
+        html! { <div id="rollhistory">
+            {for self.props.log.iter().enumerate().rev().map(|(i, record)| self.view_record(i, record))}
+        </div> }
+    }
+}
+
+impl RollHistory {
+    fn view_record(&self, i: usize, record: &RollRecord) -> Html {
+        let time = Date::new(&JsValue::from_f64(record.time.timestamp_millis() as f64));
+        let who = record
+            .roller
+            .as_ref()
+            .map(|r| r.name(&self.props.characters));
+        let opened = record
+            .sealed
+            .as_ref()
+            .zip(self.props.hidden_key)
+            .and_then(|(s, key)| s.open(&key));
+        let result = record.result.as_ref().or_else(|| opened.as_ref());
+
+        let outcome = match result {
+            Some(result) => {
+                let dice = result
+                    .dice()
+                    .map(|d| d.value.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!(": {} = {}", dice, result.total())
+            }
+            None => ": ?".to_string(),
+        };
+        let hidden = match (&record.sealed, record.revealed) {
+            (None, _) => html! {},
+            (Some(_), Some(_)) => html! {" (revealed)"},
+            (Some(_), None) if opened.is_some() => html! {<>
+                {" (hidden) "}
+                {if record.reveal_requested {
+                    html! {}
+                } else {
+                    html! {<button onclick=self.props.cb.reform(move |_| Some(GameTransition::Reveal(i)))>{"Reveal"}</button>}
+                }}
+            </>},
+            (Some(_), None) => html! {" (hidden roll by the GM)"},
+        };
+
+        html! { <div class="rollrecord">
+            <span class="time">{String::from(time.to_locale_time_string("default"))}</span>
+            {" "}
+            {if let Some(who) = who {
+                html!{<span class="who">{who}</span>}
+            } else {
+                html!{}
+            }}
+            {" "}
+            <span class="expression">{record.expression.to_string()}</span>
+            {if record.rerolled.is_some() { " reroll" } else { "" }}
+            {if record.influence_spent { " (influence)" } else { "" }}
+            {outcome}
+            {hidden}
+        </div> }
+    }
+}
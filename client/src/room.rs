@@ -0,0 +1,297 @@
+use crate::connection::{Connection, Offline};
+use crate::content::Content;
+use crate::identity::Identity;
+use aper::StateMachine;
+use chrono::{DateTime, Utc};
+use js_sys::Date;
+use state::player::{Identification, Player, PlayerKey, ServerKey};
+use state::{Game, GameTransition, ProgramTransition, HEARTBEAT_SECONDS, PRESENT_SECONDS};
+use std::time::Duration;
+use uuid::Uuid;
+use yew::prelude::*;
+use yew::services::interval::{IntervalService, IntervalTask};
+
+/// Shows the game in one room, asking the server to open the room first if
+/// it isn't open yet.
+///
+/// When the server's heartbeat stops, changes go to a local copy of the game
+/// and are sent once it is back.
+pub struct RoomGate {
+    props: Props,
+    identity: Identity,
+    joined: bool,
+    identified: bool,
+    /// When the heartbeat last moved, from `Date::now`.
+    last_heard: f64,
+    /// When the server was last told this connection is still here.
+    last_present: f64,
+    offline: Option<Offline>,
+    /// Set when the server is heard from again while offline, until the
+    /// room is open and this player identified, so the queue can be sent.
+    reconnecting: bool,
+    /// Queued changes held back because of a conflict, and the names of the
+    /// characters it is about.
+    conflict: Option<(Offline, Vec<String>)>,
+    _interval: IntervalTask,
+    link: ComponentLink<Self>,
+}
+
+#[derive(Properties, Clone)]
+pub struct Props {
+    pub name: String,
+    pub game: Option<Game>,
+    pub players: Vec<Player>,
+    pub heartbeat: Option<DateTime<Utc>>,
+    pub server_key: Option<ServerKey>,
+    /// The server could not read the room's save.
+    pub unavailable: bool,
+    pub cb: Callback<Option<ProgramTransition>>,
+}
+
+pub enum Msg {
+    SetName(String),
+    Send(GameTransition),
+    /// Checks that the server is still there, and tells it this
+    /// connection is.
+    Tick,
+    /// Whether to send the changes held back by a conflict anyway.
+    ResolveConflict(bool),
+}
+
+impl Component for RoomGate {
+    type Message = Msg;
+    type Properties = Props;
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            props,
+            identity: Identity::load(),
+            joined: false,
+            identified: false,
+            last_heard: Date::now(),
+            last_present: Date::now(),
+            offline: None,
+            reconnecting: false,
+            conflict: None,
+            _interval: IntervalService::spawn(
+                Duration::from_secs(HEARTBEAT_SECONDS as u64),
+                link.callback(|_| Msg::Tick),
+            ),
+            link,
+        }
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::SetName(name) => {
+                self.identity.set_name(name);
+                self.identified = false;
+            }
+            Msg::Send(transition) => {
+                let me = self.me().map(|(_, k)| k);
+                match &mut self.offline {
+                    Some(offline) => {
+                        offline.local.apply_as(me, transition.clone());
+                        offline.queue.push(transition);
+                    }
+                    None => {
+                        self.send(transition);
+                        return false;
+                    }
+                }
+            }
+            Msg::Tick => {
+                let quiet = Date::now() - self.last_present;
+                if self.offline.is_none() && quiet >= 1000.0 * PRESENT_SECONDS as f64 {
+                    self.last_present = Date::now();
+                    self.props.cb.emit(Some(ProgramTransition::Present));
+                }
+                let silent = Date::now() - self.last_heard;
+                if silent < 3.0 * 1000.0 * HEARTBEAT_SECONDS as f64 {
+                    return false;
+                }
+                if self.offline.is_some() {
+                    // Lost the server again before getting back in
+                    self.reconnecting = false;
+                    return false;
+                }
+                match &self.props.game {
+                    Some(game) => self.offline = Some(Offline::new(game)),
+                    None => return false,
+                }
+            }
+            Msg::ResolveConflict(send) => {
+                if let Some((offline, _)) = self.conflict.take() {
+                    if send {
+                        offline.queue.into_iter().for_each(|t| self.send(t));
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        // The room was closed for being idle while we were looking at it
+        if self.props.game.is_some() && props.game.is_none() {
+            self.joined = false;
+        }
+        let heard = self.props.heartbeat != props.heartbeat;
+        // The server restarted, and only accepts identifications made for it
+        if self.props.server_key != props.server_key {
+            self.identified = false;
+        }
+        self.props = props;
+
+        if heard {
+            self.last_heard = Date::now();
+            if self.offline.is_some() && !self.reconnecting {
+                // A new connection, which the server doesn't know yet. After
+                // a restart the room isn't even open, and joining opens it.
+                self.reconnecting = true;
+                self.identified = false;
+                self.joined = false;
+                self.identify();
+                self.join();
+            }
+        }
+        // The server announces players before it opens rooms, so once the
+        // room is back changes are no longer made by nobody
+        let known = self.identity.name.is_empty()
+            || self.props.players.iter().any(|p| p.key == self.identity.key);
+        if let (true, true, Some(game)) = (self.reconnecting, known, self.props.game.clone()) {
+            self.reconnecting = false;
+            if let Some(offline) = self.offline.take() {
+                let conflicts = offline.conflicts(&game);
+                if conflicts.is_empty() {
+                    offline.queue.into_iter().for_each(|t| self.send(t));
+                } else {
+                    let names = conflicts
+                        .iter()
+                        .filter_map(|id| game.characters.iter().find(|c| c.id == *id))
+                        .map(|c| c.value.name.to_string())
+                        .collect();
+                    self.conflict = Some((offline, names));
+                }
+            }
+        }
+        true
+    }
+
+    fn rendered(&mut self, _first_render: bool) {
+        if self.offline.is_some() {
+            return;
+        }
+        self.identify();
+        self.join();
+    }
+
+    fn view(&self) -> Html {
+        let name_input = html! {<div id="identity">
+            {"Your name: "}
+            <input type="text" value=&self.identity.name
+                onchange=self.link.callback(|i: ChangeData| match i {
+                    ChangeData::Value(v) => Msg::SetName(v),
+                    _ => unreachable!()
+                })/>
+        </div>};
+
+        let game = match &self.offline {
+            Some(offline) => Some(&offline.local),
+            None => self.props.game.as_ref(),
+        };
+        let connection = match (&self.offline, &self.conflict) {
+            (Some(offline), _) => Connection::Offline {
+                queued: offline.queue.len(),
+            },
+            (None, Some((_, names))) => Connection::Conflict {
+                characters: names.clone(),
+            },
+            (None, None) => Connection::Online,
+        };
+
+        let content = match game {
+            Some(game) => {
+                let cb = self
+                    .link
+                    .batch_callback(|t: Option<GameTransition>| t.map(Msg::Send).into_iter().collect());
+                html! { <Content room=self.props.name.clone() state=game.clone() cb=cb players=self.props.players.clone() me=self.me()
+                    shared_key=self.props.server_key.map(|s| self.identity.secret.shared(&s.key))
+                    connection=connection
+                    resolve=self.link.callback(Msg::ResolveConflict) /> }
+            }
+            None if self.props.unavailable => {
+                let name = self.props.name.clone();
+                html! {<div>
+                    {format!("Room {} could not be loaded. Its save is kept as it was; \
+                        whoever runs the server can find out more in its log. ", self.props.name)}
+                    <button onclick=self.props.cb.reform(move |_| Some(ProgramTransition::Join(name.clone())))>
+                        {"Try again"}
+                    </button>
+                </div>}
+            }
+            None => html! { <div>{format!("Joining room {}…", self.props.name)}</div> },
+        };
+
+        html! {<>
+            {name_input}
+            {content}
+        </>}
+    }
+}
+
+impl RoomGate {
+    fn me(&self) -> Option<(String, PlayerKey)> {
+        if self.identity.name.is_empty() {
+            None
+        } else {
+            Some((
+                self.identity.name.clone(),
+                self.identity.key,
+            ))
+        }
+    }
+
+    fn identify(&mut self) {
+        let server = match self.props.server_key {
+            Some(server) if !self.identified && !self.identity.name.is_empty() => server,
+            _ => return,
+        };
+        self.identified = true;
+        self.props.cb.emit(Some(ProgramTransition::Identify(
+            self.identity.name.clone(),
+            Identification::new(&self.identity.secret, &server, Uuid::new_v4()),
+        )));
+    }
+
+    /// Even an open room has to be joined before this connection may change
+    /// it.
+    fn join(&mut self) {
+        if !self.joined {
+            self.joined = true;
+            self.props
+                .cb
+                .emit(Some(ProgramTransition::Join(self.props.name.clone())));
+        }
+    }
+
+    fn send(&self, transition: GameTransition) {
+        self.props.cb.emit(Some(ProgramTransition::InRoom(
+            self.props.name.clone(),
+            transition,
+        )));
+    }
+}
+
+/// The room is chosen by the page's path, `/room/<name>`. Any other path
+/// shows the default room.
+pub fn room_from_url() -> String {
+    web_sys::window()
+        .and_then(|w| w.location().pathname().ok())
+        .and_then(|path| {
+            path.strip_prefix("/room/")
+                .map(|name| name.trim_end_matches('/').to_string())
+        })
+        .filter(|name| state::valid_room_name(name))
+        .unwrap_or_else(|| "default".to_string())
+}
@@ -1,35 +1,94 @@
-use aper::data_structures::List;
+use crate::autosave::{self, Autosave};
+use aper::data_structures::{List, ListOperation};
+use aper::StateMachine;
 use gloo_file::{
     callbacks::{read_as_bytes, FileReader},
     Blob, FileList,
 };
+use chrono::{TimeZone, Utc};
 use js_sys::Date;
-use state::Character;
+use state::import::{import_characters, Conflict};
+use state::save::SaveFile;
+use state::{Character, Game};
+use uuid::Uuid;
 use web_sys::{HtmlInputElement, Url};
 use yew::prelude::*;
 
 pub struct SaveButton {
     save: Option<Save>,
     _file_read: Option<FileReader>,
+    /// A save that has been read and waits for the player to confirm it.
+    loaded: Option<SaveFile>,
+    load_error: Option<String>,
+    autosave: Autosave,
+    /// The player has said no to restoring an autosave into an empty room.
+    dismissed: bool,
     fileselect: NodeRef,
     props: Props,
     link: ComponentLink<Self>,
 }
 
+/// A file ready to download. The object URL is released when it is dropped.
 struct Save {
     name: String,
     object_url: String,
 }
 
+impl Save {
+    /// Names the file after `prefix` and the time it was made.
+    fn new(prefix: &str, game: &Game, room: &str) -> Self {
+        let date = Date::new_0();
+        let save = SaveFile::new(
+            Some(room.to_string()),
+            Utc.timestamp_millis(date.get_time() as i64),
+            game,
+        );
+        let blob = Blob::new(&*save.to_json());
+
+        let day: String = date.to_date_string().into();
+        let name = format!("{}{} {}:{}", prefix, day, date.get_hours(), date.get_minutes());
+
+        Save {
+            name,
+            object_url: Url::create_object_url_with_blob(&blob.into()).unwrap(),
+        }
+    }
+
+    fn view(&self) -> Html {
+        let href: yew::html::Href = self.object_url.clone().into();
+        html! {<a download=self.name.clone()+".json" href=href>{format!("Download {}", self.name)}</a>}
+    }
+}
+
+impl Drop for Save {
+    fn drop(&mut self) {
+        Url::revoke_object_url(&self.object_url).unwrap();
+    }
+}
+
 #[derive(Properties, Clone)]
 pub struct Props {
-    pub characters: List<Character>,
+    pub room: String,
+    pub game: Game,
+    pub restore: Callback<Game>,
     pub load: Callback<List<Character>>,
+    pub import: Callback<Vec<ListOperation<Character>>>,
+    /// Restoring and loading replace what everyone sees, so only the GM may
+    /// do it once the room has one. Anybody may import.
+    pub can_load: bool,
 }
 
 pub enum Msg {
     GenerateSave,
     Load,
+    Loaded(Result<SaveFile, String>),
+    ConfirmRestore,
+    ConfirmLoad,
+    ConfirmImport(Conflict),
+    CancelLoad,
+    /// Shows the autosave at this index for loading.
+    PickAutosave(usize),
+    Dismiss,
 }
 use Msg::*;
 
@@ -39,9 +98,15 @@ impl Component for SaveButton {
     type Properties = Props;
 
     fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        let mut autosave = Autosave::load(&props.room);
+        autosave.record(&props.game);
         Self {
             save: None,
             _file_read: None,
+            loaded: None,
+            load_error: None,
+            autosave,
+            dismissed: false,
             fileselect: Default::default(),
             props,
             link,
@@ -51,57 +116,275 @@ impl Component for SaveButton {
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
             GenerateSave => {
-                if let Some(Save { object_url, .. }) = &self.save {
-                    Url::revoke_object_url(object_url).unwrap();
-                }
-
-                let blob = Blob::new(&*serde_json::to_string(&self.props.characters).unwrap());
-
-                let date = Date::new_0();
-                let day: String = date.to_date_string().into();
-                let name = format!("{} {}:{}", day, date.get_hours(), date.get_minutes());
-
-                self.save = Some(Save {
-                    name,
-                    object_url: Url::create_object_url_with_blob(&blob.into()).unwrap(),
-                });
+                self.save = Some(Save::new("", &self.props.game, &self.props.room));
                 true
             }
             Load => {
-                let filelist: FileList = self
-                    .fileselect
-                    .cast::<HtmlInputElement>()
-                    .unwrap()
-                    .files()
-                    .unwrap()
-                    .into();
-                let load = self.props.load.clone();
-                self._file_read = Some(read_as_bytes(&filelist[0], move |res| {
-                    load.emit(serde_json::from_slice(&res.unwrap()).unwrap());
-                }));
-
-                false
+                self.loaded = None;
+                self.load_error = None;
+                let input = self.fileselect.cast::<HtmlInputElement>().unwrap();
+                let filelist: Option<FileList> = input.files().map(Into::into);
+                // Lets the same file be picked again after cancelling
+                input.set_value("");
+
+                // Nothing is selected if the dialog was cancelled
+                if let Some(file) = filelist.as_ref().and_then(|f| f.first()) {
+                    let loaded = self.link.callback(Loaded);
+                    self._file_read = Some(read_as_bytes(file, move |res| {
+                        loaded.emit(match res {
+                            Ok(bytes) => SaveFile::from_slice(&bytes).map_err(|e| e.to_string()),
+                            Err(e) => Err(format!("the file could not be read: {}", e)),
+                        })
+                    }));
+                }
+                true
+            }
+            Loaded(res) => {
+                match res {
+                    Ok(save) => self.loaded = Some(save),
+                    Err(e) => self.load_error = Some(format!("Cannot load: {}.", e)),
+                }
+                true
+            }
+            ConfirmRestore => {
+                if let Some(save) = self.loaded.take() {
+                    self.props.restore.emit(save.game);
+                }
+                true
+            }
+            ConfirmLoad => {
+                if let Some(save) = self.loaded.take() {
+                    self.props.load.emit(save.game.characters);
+                }
+                true
+            }
+            ConfirmImport(conflict) => {
+                if let Some(save) = self.loaded.take() {
+                    self.props.import.emit(import_characters(
+                        &self.props.game.characters,
+                        &save.game.characters,
+                        conflict,
+                    ));
+                }
+                true
+            }
+            CancelLoad => {
+                self.loaded = None;
+                true
+            }
+            PickAutosave(i) => {
+                self.load_error = None;
+                self.loaded = self.autosave.snapshots().get(i).cloned();
+                true
+            }
+            Dismiss => {
+                self.dismissed = true;
+                true
             }
         }
     }
 
     fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        let recorded = self.autosave.record(&props.game);
+        let rerender = recorded
+            || self.props.can_load != props.can_load
+            || autosave::is_empty(&self.props.game) != autosave::is_empty(&props.game)
+            || (self.loaded.is_some() && self.props.game != props.game);
         self.props = props;
-        false
+        rerender
     }
 
     fn view(&self) -> Html {
-        html! { <div id="savewidget">
+        html! { <div>
+            {self.view_empty_room()}
             <button onclick=self.link.callback(|_| GenerateSave)>{"Generate Save"}</button>
             <br/>
-            {if let Some(Save{name, object_url}) = &self.save {
-                let href: yew::html::Href = object_url.clone().into();
-                html!{<a download=name.to_string()+".json" href=href>{format!("Download {}", name)}</a>}
+            {self.save.as_ref().map_or_else(|| html!{}, Save::view)}
+            <br/>
+            {"Load: "}<input type="file" accept=".json,application/json" ref=self.fileselect.clone()
+                onchange=self.link.callback(|_| Load)/>
+            {if let Some(error) = &self.load_error {
+                html!{<div class="error">{error}</div>}
             } else {
                 html!{}
             }}
-            <br/>
-            {"Load: "}<input type="file" ref=self.fileselect.clone() onchange=self.link.callback(|_| Load)/>
+            {self.view_autosaves()}
+            {self.view_preview()}
         </div> }
     }
 }
+
+impl SaveButton {
+    /// Offers the latest autosave when the room is empty, which usually
+    /// means the server lost it.
+    fn view_empty_room(&self) -> Html {
+        let latest = self.autosave.snapshots().len().checked_sub(1);
+        match latest {
+            Some(i) if !self.dismissed && self.loaded.is_none() && autosave::is_empty(&self.props.game) => {
+                let save = &self.autosave.snapshots()[i];
+                html! {<div class="loadpreview">
+                    {format!(
+                        "This room is empty, but this browser kept a copy of it with {} characters{}. ",
+                        save.game.characters.iter().count(),
+                        save.saved.map_or(String::new(), |t| format!(" from {}", t.format("%Y-%m-%d %H:%M UTC"))),
+                    )}
+                    <button onclick=self.link.callback(move |_| PickAutosave(i))>{"Restore it"}</button>
+                    <button onclick=self.link.callback(|_| Dismiss)>{"No"}</button>
+                </div>}
+            }
+            _ => html! {},
+        }
+    }
+
+    fn view_autosaves(&self) -> Html {
+        let snapshots = self.autosave.snapshots();
+        if snapshots.is_empty() {
+            return html! {};
+        }
+        let items = snapshots.iter().enumerate().rev().map(|(i, save)| {
+            let time = save
+                .saved
+                .map_or("unknown time".to_string(), |t| t.format("%Y-%m-%d %H:%M UTC").to_string());
+            html! {<li>
+                {format!("{}, {} characters ", time, save.game.characters.iter().count())}
+                <button onclick=self.link.callback(move |_| PickAutosave(i))>{"Load…"}</button>
+            </li>}
+        });
+        html! {<details>
+            <summary>{format!("Autosaves in this browser ({})", snapshots.len())}</summary>
+            <ul>{for items}</ul>
+        </details>}
+    }
+
+    /// Shows what loading the save would change before anything happens,
+    /// since replacing affects everyone in the room.
+    fn view_preview(&self) -> Html {
+        let save = match &self.loaded {
+            Some(save) => save,
+            None => return html! {},
+        };
+        let names = |characters: &List<Character>| -> Vec<Html> {
+            characters
+                .iter()
+                .map(|c| html! {<li>{c.value.name.to_string()}</li>})
+                .collect()
+        };
+        let from = match (&save.room, save.saved) {
+            (Some(room), Some(saved)) => format!(" from room {}, saved {}", room, saved.format("%Y-%m-%d %H:%M UTC")),
+            (Some(room), None) => format!(" from room {}", room),
+            (None, Some(saved)) => format!(", saved {}", saved.format("%Y-%m-%d %H:%M UTC")),
+            (None, None) => String::new(),
+        };
+        let duplicates = save
+            .game
+            .characters
+            .iter()
+            .filter(|c| {
+                self.props
+                    .game
+                    .characters
+                    .iter()
+                    .any(|d| d.id == c.id || d.value.name == c.value.name)
+            })
+            .count();
+
+        html! {<div class="loadpreview">
+            <div>{format!("Load this save{}?", from)}</div>
+            <div>{format!("It has {} rolls in its history.", save.game.log.len())}</div>
+            <div>{"Characters in the save:"}</div>
+            <ul>{for names(&save.game.characters)}</ul>
+            <div>{"Replacing removes:"}</div>
+            <ul>{for names(&self.props.game.characters)}</ul>
+            <button onclick=self.link.callback(|_| ConfirmRestore) disabled=!self.props.can_load>
+                {"Restore everything"}
+            </button>
+            <button onclick=self.link.callback(|_| ConfirmLoad) disabled=!self.props.can_load>
+                {"Replace characters only"}
+            </button>
+            <button onclick=self.link.callback(|_| ConfirmImport(Conflict::KeepBoth))>{"Add"}</button>
+            {if duplicates > 0 {
+                html!{<>
+                    <div>{format!("{} of them are already in the game. Adding keeps both, or:", duplicates)}</div>
+                    <button onclick=self.link.callback(|_| ConfirmImport(Conflict::Overwrite))>
+                        {"Add, overwriting duplicates"}
+                    </button>
+                    <button onclick=self.link.callback(|_| ConfirmImport(Conflict::Skip))>
+                        {"Add, skipping duplicates"}
+                    </button>
+                </>}
+            } else {
+                html!{}
+            }}
+            <button onclick=self.link.callback(|_| CancelLoad)>{"Cancel"}</button>
+        </div>}
+    }
+}
+
+/// Downloads a single character, in the same format as [`SaveButton`] so
+/// that it can be loaded or imported elsewhere.
+pub struct ExportButton {
+    save: Option<Save>,
+    props: ExportProps,
+    link: ComponentLink<Self>,
+}
+
+#[derive(Properties, Clone)]
+pub struct ExportProps {
+    pub room: String,
+    pub characters: List<Character>,
+    pub character: Uuid,
+}
+
+impl Component for ExportButton {
+    type Message = ();
+    type Properties = ExportProps;
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            save: None,
+            props,
+            link,
+        }
+    }
+
+    fn update(&mut self, _: Self::Message) -> ShouldRender {
+        // Deleting the others keeps the character's ID and position
+        let mut characters = self.props.characters.clone();
+        let others: Vec<Uuid> = characters
+            .iter()
+            .map(|c| c.id)
+            .filter(|id| *id != self.props.character)
+            .collect();
+        for id in others {
+            let op = characters.delete(id);
+            characters.apply(op);
+        }
+
+        let name = characters
+            .iter()
+            .next()
+            .map(|c| c.value.name.to_string())
+            .unwrap_or_default();
+        let game = Game {
+            characters,
+            ..Game::default()
+        };
+        self.save = Some(Save::new(&(name + " "), &game, &self.props.room));
+        true
+    }
+
+    fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        if self.props.character != props.character {
+            self.save = None;
+        }
+        self.props = props;
+        true
+    }
+
+    fn view(&self) -> Html {
+        html! {<>
+            <button onclick=self.link.callback(|_| ())>{"Export"}</button>
+            {self.save.as_ref().map_or_else(|| html!{}, Save::view)}
+        </>}
+    }
+}
@@ -0,0 +1,188 @@
+use aper::StateMachine;
+use state::text::{CharId, Text, TextTransition};
+use uuid::Uuid;
+use wasm_bindgen::JsValue;
+use wasm_bindgen::JsCast;
+use web_sys::{HtmlInputElement, HtmlTextAreaElement};
+use yew::prelude::*;
+
+/// A text field that sends every keystroke as an edit to a [`Text`].
+///
+/// Changes made by others while somebody types here are merged into the
+/// field instead of replacing it: it shows `text` with the edits made here
+/// that haven't come back yet, and the caret keeps its place among the
+/// characters around it.
+pub struct TextInput {
+    props: Props,
+    /// What the field shows.
+    local: Text,
+    /// Edits sent from here that `props.text` doesn't have yet.
+    pending: Vec<TextTransition>,
+    /// Where to put the caret back after rendering, if the field has focus.
+    caret: Option<Option<CharId>>,
+    input: NodeRef,
+    link: ComponentLink<Self>,
+}
+
+#[derive(Properties, Clone, PartialEq)]
+pub struct Props {
+    pub text: Text,
+    #[prop_or_default]
+    pub class: String,
+    pub cb: Callback<TextTransition>,
+    /// Shows a `<textarea>` instead of a single line.
+    #[prop_or_default]
+    pub multiline: bool,
+    /// The field's value once it loses focus.
+    #[prop_or_default]
+    pub onchange: Callback<String>,
+    #[prop_or_default]
+    pub onkeydown: Callback<KeyboardEvent>,
+}
+
+pub enum Msg {
+    Input(String),
+}
+
+impl Component for TextInput {
+    type Message = Msg;
+    type Properties = Props;
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            local: props.text.clone(),
+            props,
+            pending: Vec::new(),
+            caret: None,
+            input: NodeRef::default(),
+            link,
+        }
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::Input(value) => {
+                let edit = self.local.edit(&value, Uuid::new_v4());
+                self.local.apply(edit.clone());
+                self.pending.push(edit.clone());
+                self.props.cb.emit(edit);
+            }
+        }
+        false
+    }
+
+    fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        if self.props == props {
+            return false;
+        }
+        self.caret = self.focused().map(|field| {
+            let start = field.selection_start().unwrap_or(0);
+            self.local.anchor(chars_before(&field.value(), start))
+        });
+
+        // Applying an edit twice changes nothing, so the ones that do
+        // change `text` are still on their way
+        let text = &props.text;
+        self.pending.retain(|edit| {
+            let mut applied = text.clone();
+            applied.apply(edit.clone());
+            applied != *text
+        });
+        let mut local = text.clone();
+        for edit in &self.pending {
+            local.apply(edit.clone());
+        }
+        self.local = local;
+        self.props = props;
+        true
+    }
+
+    fn rendered(&mut self, _first_render: bool) {
+        if let (Some(anchor), Some(field)) = (self.caret.take(), self.focused()) {
+            let at = utf16_len(&field.value(), self.local.position(anchor));
+            field.set_caret(at);
+        }
+    }
+
+    fn view(&self) -> Html {
+        let oninput = self.link.callback(|e: InputData| Msg::Input(e.value));
+        let onchange = self.props.onchange.reform(|i: ChangeData| match i {
+            ChangeData::Value(v) => v,
+            _ => unreachable!(),
+        });
+        if self.props.multiline {
+            html! {
+                <textarea ref=self.input.clone() class=self.props.class.clone()
+                    value=self.local.to_string()
+                    oninput=oninput onchange=onchange
+                    onkeydown=self.props.onkeydown.clone()/>
+            }
+        } else {
+            html! {
+                <input type="text" ref=self.input.clone() class=self.props.class.clone()
+                    value=self.local.to_string()
+                    oninput=oninput onchange=onchange
+                    onkeydown=self.props.onkeydown.clone()/>
+            }
+        }
+    }
+}
+
+impl TextInput {
+    fn focused(&self) -> Option<Field> {
+        let node = self.input.get()?;
+        let active = web_sys::window()?.document()?.active_element()?;
+        if JsValue::from(active) != JsValue::from(node.clone()) {
+            return None;
+        }
+        match node.dyn_into::<HtmlInputElement>() {
+            Ok(input) => Some(Field::Input(input)),
+            Err(node) => node.dyn_into().ok().map(Field::TextArea),
+        }
+    }
+}
+
+/// The element a [`TextInput`] is shown as.
+enum Field {
+    Input(HtmlInputElement),
+    TextArea(HtmlTextAreaElement),
+}
+
+impl Field {
+    fn value(&self) -> String {
+        match self {
+            Field::Input(input) => input.value(),
+            Field::TextArea(textarea) => textarea.value(),
+        }
+    }
+
+    fn selection_start(&self) -> Option<u32> {
+        match self {
+            Field::Input(input) => input.selection_start().ok().flatten(),
+            Field::TextArea(textarea) => textarea.selection_start().ok().flatten(),
+        }
+    }
+
+    fn set_caret(&self, at: u32) {
+        match self {
+            Field::Input(input) => input.set_selection_range(at, at).ok(),
+            Field::TextArea(textarea) => textarea.set_selection_range(at, at).ok(),
+        };
+    }
+}
+
+/// Browsers count the caret's position in UTF-16 code units.
+fn chars_before(value: &str, utf16: u32) -> usize {
+    let mut units = 0;
+    value
+        .chars()
+        .take_while(|c| {
+            units += c.len_utf16() as u32;
+            units <= utf16
+        })
+        .count()
+}
+
+fn utf16_len(value: &str, chars: usize) -> u32 {
+    value.chars().take(chars).map(|c| c.len_utf16() as u32).sum()
+}
@@ -0,0 +1,115 @@
+use chrono::Utc;
+use state::journal::{self, JournalEntry, JournalLine};
+use state::Game;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A journal this long is folded into a fresh one starting from the current
+/// game.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Every transition applied to an open room, appended to
+/// `<room>.journal` as it happens.
+///
+/// A journal starts with the game the room was opened with, so replaying
+/// it on its own rebuilds the room after a crash. When a room is closed its
+/// journal is moved to `journal/<room>/` and kept as a record of the session.
+pub struct Journals {
+    dir: PathBuf,
+    open: Mutex<HashMap<String, Open>>,
+}
+
+struct Open {
+    file: File,
+    entries: usize,
+}
+
+impl Journals {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn path(&self, room: &str) -> PathBuf {
+        self.dir.join(format!("{}.journal", room))
+    }
+
+    /// Rebuilds a room from its journal, if it has one that was never
+    /// archived.
+    pub fn read(&self, room: &str) -> io::Result<Option<Game>> {
+        match read(&self.path(room)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            result => result.map(Some),
+        }
+    }
+
+    /// Starts the room's journal from `game`. A journal left from before a
+    /// restart, which `game` was replayed from, is archived rather than
+    /// appended to, as its last line may have been cut short.
+    pub fn start(&self, room: &str, game: &Game) -> io::Result<()> {
+        self.archive(room)?;
+        let file = create(&self.path(room), game)?;
+        self.open
+            .lock()
+            .unwrap()
+            .insert(room.to_string(), Open { file, entries: 0 });
+        Ok(())
+    }
+
+    /// `game` is the room after `entry` was applied.
+    pub fn append(&self, room: &str, entry: &JournalEntry, game: &Game) -> io::Result<()> {
+        let mut open = self.open.lock().unwrap();
+        let journal = match open.get_mut(room) {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        journal
+            .file
+            .write_all(JournalLine::Applied(entry.clone()).to_line().as_bytes())?;
+        journal.entries += 1;
+
+        if journal.entries >= MAX_ENTRIES {
+            drop(open);
+            self.start(room, game)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the room's journal to `journal/<room>/`, named after the time
+    /// it was archived. Call once the room's snapshot is written, as the
+    /// snapshot is all that is left afterwards.
+    pub fn archive(&self, room: &str) -> io::Result<()> {
+        self.open.lock().unwrap().remove(room);
+        let path = self.path(room);
+        if !path.exists() {
+            return Ok(());
+        }
+        let archive = self.dir.join("journal").join(room);
+        fs::create_dir_all(&archive)?;
+        let name = format!("{}.journal", Utc::now().format("%Y-%m-%dT%H-%M-%S%.3f"));
+        fs::rename(path, archive.join(name))
+    }
+}
+
+fn create(path: &Path, game: &Game) -> io::Result<File> {
+    let mut file = OpenOptions::new().append(true).create_new(true).open(path)?;
+    file.write_all(JournalLine::Start(game.clone()).to_line().as_bytes())?;
+    file.sync_data()?;
+    Ok(file)
+}
+
+/// Replays a journal file, live or archived.
+pub fn read(path: &Path) -> io::Result<Game> {
+    let text = fs::read_to_string(path)?;
+    journal::replay(journal::parse(&text)).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} does not start with a game", path.display()),
+        )
+    })
+}
@@ -1,12 +1,142 @@
-use aper::StateMachineContainerProgram;
+mod journal;
+mod snapshots;
+
 use aper_actix::ServerBuilder;
-use state::Game;
+use snapshots::Snapshots;
+use state::player::SecretKey;
+use state::save::SaveFile;
+use state::{valid_room_name, Game, GameProgram};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Server for roll4. Most options can also be set with the environment
+/// variable shown next to them.
+#[derive(StructOpt)]
+struct Options {
+    /// Address to listen on
+    #[structopt(long, env = "ROLL4_ADDRESS", default_value = "127.0.0.1")]
+    address: String,
+
+    /// Port to listen on
+    #[structopt(short, long, env = "ROLL4_PORT", default_value = "8000")]
+    port: u16,
+
+    /// Directory holding the built client (`static/`) and the character
+    /// sheet assets (`static-client/`). The server runs from there.
+    #[structopt(long, env = "ROLL4_STATIC_DIR", parse(from_os_str))]
+    static_dir: Option<PathBuf>,
+
+    /// Directory where rooms are saved
+    #[structopt(long, env = "ROLL4_SAVE_DIR", default_value = "saves", parse(from_os_str))]
+    save_dir: PathBuf,
+
+    /// Log filter, for example `info` or `roll4=debug,warn`
+    #[structopt(long, env = "ROLL4_LOG", default_value = "info")]
+    log_level: String,
+
+    /// Room that --new and --load apply to
+    #[structopt(long, env = "ROLL4_ROOM", default_value = "default")]
+    room: String,
+
+    /// Start the room with an empty game instead of its saved one. There
+    /// is no environment variable for this, which would start the room over
+    /// on every restart
+    #[structopt(long, conflicts_with = "load")]
+    new: bool,
+
+    /// Start the room with the game or character list in this file. A
+    /// journal is replayed, which restores the room as it was at its end
+    #[structopt(long, env = "ROLL4_LOAD", parse(from_os_str))]
+    load: Option<PathBuf>,
+}
 
 fn main() -> std::io::Result<()> {
-    let builder = ServerBuilder::new(StateMachineContainerProgram(Game::default()));
-    if std::env::args().len() > 1 {
-        builder.serve_on("0.0.0.0", 666)
-    } else {
-        builder.serve()
+    let options = Options::from_args();
+
+    env_logger::Builder::new()
+        .parse_filters(&options.log_level)
+        .init();
+
+    if !valid_room_name(&options.room) {
+        log::error!("{:?} is not a valid room name", options.room);
+        std::process::exit(1);
+    }
+
+    // Resolved before changing into the static directory
+    let save_dir = std::env::current_dir()?.join(&options.save_dir);
+    let snapshots = Arc::new(Snapshots::new(&save_dir)?);
+    let secret = server_key(&save_dir)?;
+
+    if options.new {
+        snapshots.replace(&options.room, &Game::default())?;
+    } else if let Some(path) = &options.load {
+        snapshots.replace(&options.room, &load_game(path)?)?;
+    }
+
+    if let Some(dir) = &options.static_dir {
+        std::env::set_current_dir(dir)?;
+    }
+
+    {
+        let snapshots = snapshots.clone();
+        thread::spawn(move || loop {
+            thread::sleep(SNAPSHOT_INTERVAL);
+            // Failures are reported by flush and retried on the next round
+            snapshots.flush().ok();
+        });
+    }
+
+    log::info!("Listening on {}:{}", options.address, options.port);
+    let result = ServerBuilder::new(GameProgram::server(snapshots.clone(), secret))
+        .serve_on(&options.address, options.port);
+
+    snapshots.flush()?;
+    result
+}
+
+/// The server's secret key, kept in `server.key` in the save directory and
+/// created the first time. Players identify against it, and hidden rolls
+/// can only be revealed by a server with the key they were made under.
+fn server_key(save_dir: &Path) -> io::Result<SecretKey> {
+    let path = save_dir.join("server.key");
+    match std::fs::read_to_string(&path) {
+        Ok(hex) => SecretKey::from_hex(hex.trim()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} does not hold a key", path.display()),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let secret = SecretKey::generate();
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(&path)?.write_all(secret.to_hex().as_bytes())?;
+            Ok(secret)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads a server snapshot, a journal, or a save made with the client's save
+/// button.
+fn load_game(path: &Path) -> std::io::Result<Game> {
+    if path.extension().map_or(false, |e| e == "journal") {
+        return journal::read(path);
+    }
+    let bytes = std::fs::read(path)?;
+    if let Ok(game) = serde_json::from_slice(&bytes) {
+        return Ok(game);
     }
+    SaveFile::from_slice(&bytes)
+        .map(|save| save.game)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
 }
@@ -0,0 +1,185 @@
+use crate::journal::Journals;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand_core::OsRng;
+use state::journal::JournalEntry;
+use state::{Game, RoomStore};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Keeps every room on disk so that it survives restarts and can be taken
+/// out of memory while nobody is using it.
+///
+/// [`RoomStore::changed`] is called after every transition and only appends
+/// to the room's [`Journals`], which is all it takes to rebuild the room
+/// after a crash. Snapshots are written when a room is closed, and by
+/// [`Snapshots::flush`] for rooms started over with [`Snapshots::replace`].
+pub struct Snapshots {
+    dir: PathBuf,
+    /// Rooms started over and not written since.
+    unsaved: Mutex<HashMap<String, Game>>,
+    journals: Journals,
+}
+
+impl Snapshots {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            journals: Journals::new(&dir),
+            dir,
+            unsaved: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Starts `room` over from `game`, whatever was saved for it before.
+    pub fn replace(&self, room: &str, game: &Game) -> io::Result<()> {
+        self.unsaved
+            .lock()
+            .unwrap()
+            .insert(room.to_string(), game.clone());
+        self.journals.start(room, game)
+    }
+
+    /// Room names are checked by [`state::valid_room_name`] before a room is
+    /// opened, so they are safe to use as file names.
+    fn path(&self, room: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", room))
+    }
+
+    fn read(&self, room: &str) -> io::Result<Option<Game>> {
+        let mut path = self.path(room);
+        if room == "default" && !path.exists() {
+            // Saved before the server had rooms
+            path = self.dir.join("game.json");
+        }
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Where the hash of a room's GM password is kept. It stays next to the
+    /// room's snapshot and is never sent to clients.
+    fn password_path(&self, room: &str) -> PathBuf {
+        self.dir.join(format!("{}.gm", room))
+    }
+
+    fn write(&self, room: &str, game: &Game) -> io::Result<()> {
+        write_atomically(&self.path(room), &serde_json::to_vec(game)?)
+    }
+
+    /// Writes every room started over since it was last written, and
+    /// returns the last error if any of them failed.
+    pub fn flush(&self) -> io::Result<()> {
+        // Holding the lock while writing keeps an older copy from being
+        // written over the one saved when a room is closed.
+        let mut unsaved = self.unsaved.lock().unwrap();
+        let mut result = Ok(());
+        for (room, game) in unsaved.drain() {
+            if let Err(e) = self.write(&room, &game) {
+                log::error!("Failed to save room {}: {}", room, e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+impl RoomStore for Snapshots {
+    fn load(&self, room: &str) -> io::Result<Option<Game>> {
+        if let Some(game) = self.unsaved.lock().unwrap().get(room) {
+            return Ok(Some(game.clone()));
+        }
+        // A journal is only left behind if the server stopped without
+        // closing the room, and is newer than the snapshot then
+        match self.journals.read(room) {
+            Ok(Some(game)) => return Ok(Some(game)),
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to replay the journal of room {}: {}", room, e),
+        }
+        self.read(room).map_err(|e| {
+            log::error!("Failed to load room {}, which stays closed: {}", room, e);
+            e
+        })
+    }
+
+    fn opened(&self, room: &str, game: &Game) {
+        if let Err(e) = self.journals.start(room, game) {
+            log::error!("Failed to start the journal of room {}: {}", room, e);
+        }
+    }
+
+    fn changed(&self, room: &str, game: &Game, entry: &JournalEntry) {
+        if let Err(e) = self.journals.append(room, entry, game) {
+            log::error!("Failed to write to the journal of room {}: {}", room, e);
+        }
+    }
+
+    fn closed(&self, room: &str, game: &Game) {
+        let mut unsaved = self.unsaved.lock().unwrap();
+        unsaved.remove(room);
+        if let Err(e) = self.write(room, game) {
+            // The journal still has everything
+            log::error!("Failed to save room {}: {}", room, e);
+            return;
+        }
+        if let Err(e) = self.journals.archive(room) {
+            log::error!("Failed to archive the journal of room {}: {}", room, e);
+        }
+    }
+
+    fn claim_gm(&self, room: &str, password: &str, may_set: bool) -> bool {
+        let path = self.password_path(room);
+        match fs::read_to_string(&path) {
+            Ok(hash) => match PasswordHash::new(hash.trim()) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(e) => {
+                    log::error!("Failed to read the GM password of room {}: {}", room, e);
+                    false
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound && may_set => {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = match Argon2::default().hash_password(password.as_bytes(), &salt) {
+                    Ok(hash) => hash.to_string(),
+                    Err(e) => {
+                        log::error!("Failed to hash the GM password of room {}: {}", room, e);
+                        return false;
+                    }
+                };
+                match write_atomically(&path, hash.as_bytes()) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::error!("Failed to save the GM password of room {}: {}", room, e);
+                        false
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => {
+                log::error!("Failed to read the GM password of room {}: {}", room, e);
+                false
+            }
+        }
+    }
+}
+
+/// Writes to a temporary file first and renames it over `path`, so a crash
+/// leaves either the old or the new file, never half of one.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    // Named after the whole file name, as rooms have several files
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
@@ -1,34 +1,272 @@
-use aper::{data_structures::{Atom, List}, StateMachine};
+use crate::stat::Stat;
+use crate::text::Text;
+use aper::{data_structures::{Atom, List, ListItem, ListOperation}, StateMachine};
 use serde::{Deserialize, Serialize};
 
+/// Body and mind: 1 is the worst condition, 3 is ok.
+pub type Condition = Stat<u8, 1, 3>;
+/// Memory and influence points.
+pub type Points = Stat<u8, 0, 9>;
+pub type Gravity = Stat<i8, { -5 }, 5>;
+
 #[derive(StateMachine, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(from = "CharacterData")]
 pub struct Character {
+    pub name: Text,
+    pub habitat: Text,
+    pub body: Condition,
+    pub mind: Condition,
+    pub memory_points: Points,
+    pub influence_points: Points,
+    #[serde(rename = "gravity")]
+    pub preferred_gravity: Gravity,
+    pub notes: List<Text>,
+    pub effects: List<Effect>,
+    /// Archived characters are kept off the tab strip until restored.
+    pub archived: Atom<bool>,
+    /// Long-form Markdown, one paragraph per item so that paragraphs can be
+    /// added and moved while people type in them.
+    pub journal: List<Text>,
+}
+
+/// What a [`Character`] may look like in a save, including older formats.
+#[derive(Deserialize)]
+struct CharacterData {
+    name: Text,
+    habitat: Text,
+    body: Condition,
+    mind: Condition,
+    memory_points: Points,
+    influence_points: Points,
+    gravity: Option<Gravity>,
+    /// Before gravity was signed it was stored as 0 to 10, 5 meaning 0.
+    preferred_gravity: Option<Atom<u8>>,
+    notes: NotesData,
+    #[serde(default)]
+    effects: List<Effect>,
+    archived: Option<Atom<bool>>,
+    /// Paragraphs used to be atoms, which `Text` still reads.
+    #[serde(default)]
+    journal: List<Text>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NotesData {
+    /// Notes used to be atoms, and characters started with eight empty
+    /// ones.
+    Atoms(List<Atom<String>>),
+    Text(List<Text>),
+}
+
+impl From<NotesData> for List<Text> {
+    fn from(notes: NotesData) -> Self {
+        let atoms = match notes {
+            NotesData::Atoms(atoms) => atoms,
+            NotesData::Text(notes) => return notes,
+        };
+        let mut notes = List::new();
+        for ListItem { id, location, value } in atoms.iter() {
+            let note = Text::new(value.value());
+            notes.apply(ListOperation::Insert(location.clone(), id, note));
+        }
+        without_trailing_blanks(notes)
+    }
+}
+
+impl From<CharacterData> for Character {
+    fn from(c: CharacterData) -> Self {
+        let preferred_gravity = match (c.gravity, c.preferred_gravity) {
+            (Some(g), _) => g,
+            (None, Some(old)) => Gravity::new((*old.value()).min(10) as i8 - 5),
+            (None, None) => Gravity::new(0),
+        };
+
+        Self {
+            name: c.name,
+            habitat: c.habitat,
+            body: c.body,
+            mind: c.mind,
+            memory_points: c.memory_points,
+            influence_points: c.influence_points,
+            preferred_gravity,
+            notes: c.notes.into(),
+            effects: c.effects,
+            archived: c.archived.unwrap_or_else(|| Atom::new(false)),
+            journal: c.journal,
+        }
+    }
+}
+
+/// Drops the empty note lines old characters started with that were never
+/// written in. Only done to notes that were atoms, as copies of the game
+/// would otherwise differ depending on when they were loaded.
+fn without_trailing_blanks(mut notes: List<Text>) -> List<Text> {
+    let blank: Vec<_> = notes
+        .iter()
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .take_while(|n| n.value.is_empty())
+        .map(|n| n.id)
+        .collect();
+    for id in blank {
+        notes.apply(notes.delete(id));
+    }
+    notes
+}
+
+#[derive(StateMachine, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Effect {
     pub name: Atom<String>,
-    pub habitat: Atom<String>,
-    pub body: Atom<u8>,
-    pub mind: Atom<u8>,
-    pub memory_points: Atom<u8>,
-    pub influence_points: Atom<u8>,
-    pub preferred_gravity: Atom<u8>,
-    pub notes: List<Atom<String>>,
+    pub value: Atom<String>,
+}
+
+impl Effect {
+    pub fn new(name: String, value: String) -> Self {
+        Self {
+            name: Atom::new(name),
+            value: Atom::new(value),
+        }
+    }
 }
 
 impl Default for Character {
     fn default() -> Self {
-        let mut notes = List::new();
-        for _ in 0..8 {
-            notes.apply(notes.append(Atom::new("".to_string())).1);
+        Self {
+            name: Text::default(),
+            habitat: Text::new("Brighttown"),
+            body: Condition::new(3),
+            mind: Condition::new(3),
+            memory_points: Points::new(0),
+            influence_points: Points::new(1),
+            preferred_gravity: Gravity::new(0),
+            notes: List::new(),
+            effects: List::new(),
+            archived: Atom::new(false),
+            journal: List::new(),
         }
+    }
+}
 
-        Self {
-            name: Atom::new("".to_string()),
-            habitat: Atom::new("Brighttown".to_string()),
-            body: Atom::new(3),
-            mind: Atom::new(3),
-            memory_points: Atom::new(0),
-            influence_points: Atom::new(1),
-            preferred_gravity: Atom::new(5),
-            notes,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    type Transition = <Character as StateMachine>::Transition;
+
+    fn changed(transition: impl FnOnce(&Character) -> Transition) -> Character {
+        let mut character = Character::default();
+        character.apply(transition(&character));
+        character
+    }
+
+    /// Replaces the number a stat is saved as, whatever it is wrapped in.
+    fn set_number(value: &mut Value, n: i64) {
+        match value {
+            Value::Number(_) => *value = n.into(),
+            Value::Object(map) => map.values_mut().for_each(|v| set_number(v, n)),
+            Value::Array(items) => items.iter_mut().for_each(|v| set_number(v, n)),
+            _ => {}
+        }
+    }
+
+    fn loaded(field: &str, n: i64) -> Character {
+        let mut json = serde_json::to_value(Character::default()).unwrap();
+        set_number(&mut json[field], n);
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn conditions_stay_from_1_to_3() {
+        for &(n, expected) in &[(0, 1), (1, 1), (2, 2), (3, 3), (4, 3), (255, 3)] {
+            let body = changed(|c| c.map_body(|b| b.replace(n))).body;
+            let mind = changed(|c| c.map_mind(|m| m.replace(n))).mind;
+            assert_eq!(*body.value(), expected);
+            assert_eq!(*mind.value(), expected);
+            assert_eq!(*loaded("body", n.into()).body.value(), expected);
+            assert_eq!(*loaded("mind", n.into()).mind.value(), expected);
+        }
+    }
+
+    #[test]
+    fn points_stay_from_0_to_9() {
+        for &(n, expected) in &[(0, 0), (9, 9), (10, 9), (255, 9)] {
+            let memory = changed(|c| c.map_memory_points(|p| p.replace(n))).memory_points;
+            let influence = changed(|c| c.map_influence_points(|p| p.replace(n)));
+            assert_eq!(*memory.value(), expected);
+            assert_eq!(*influence.influence_points.value(), expected);
+
+            let memory = loaded("memory_points", n.into()).memory_points;
+            let influence = loaded("influence_points", n.into()).influence_points;
+            assert_eq!(*memory.value(), expected);
+            assert_eq!(*influence.value(), expected);
+        }
+    }
+
+    #[test]
+    fn gravity_stays_from_minus_5_to_5() {
+        let cases = [(-128, -5), (-6, -5), (-5, -5), (5, 5), (6, 5), (127, 5)];
+        for &(n, expected) in &cases {
+            let gravity = changed(|c| c.map_preferred_gravity(|g| g.replace(n)));
+            assert_eq!(*gravity.preferred_gravity.value(), expected);
+            let gravity = loaded("gravity", n.into()).preferred_gravity;
+            assert_eq!(*gravity.value(), expected);
+        }
+    }
+
+    #[test]
+    fn old_gravity_is_moved_and_clamped() {
+        for &(old, expected) in &[(0u8, -5), (5, 0), (10, 5), (11, 5), (255, 5)] {
+            let mut json = serde_json::to_value(Character::default()).unwrap();
+            let map = json.as_object_mut().unwrap();
+            map.remove("gravity");
+            map.insert(
+                "preferred_gravity".to_string(),
+                serde_json::to_value(Atom::new(old)).unwrap(),
+            );
+            let character: Character = serde_json::from_value(json).unwrap();
+            assert_eq!(*character.preferred_gravity.value(), expected);
+        }
+    }
+
+    #[test]
+    fn old_journal_paragraphs_are_read_as_text() {
+        let mut journal = List::new();
+        let (_, op) = journal.append(Atom::new("Met the *courier*.".to_string()));
+        journal.apply(op);
+
+        let mut json = serde_json::to_value(Character::default()).unwrap();
+        json["journal"] = serde_json::to_value(journal).unwrap();
+        let character: Character = serde_json::from_value(json).unwrap();
+        let paragraphs: Vec<String> = character
+            .journal
+            .iter()
+            .map(|p| p.value.to_string())
+            .collect();
+        assert_eq!(paragraphs, vec!["Met the *courier*.".to_string()]);
+    }
+
+    #[test]
+    fn only_old_notes_lose_trailing_blanks() {
+        let mut old = List::new();
+        for note in &["Owes the courier", "", ""] {
+            let (_, op) = old.append(Atom::new(note.to_string()));
+            old.apply(op);
+        }
+        let mut json = serde_json::to_value(Character::default()).unwrap();
+        json["notes"] = serde_json::to_value(&old).unwrap();
+        let character: Character = serde_json::from_value(json).unwrap();
+        let notes: Vec<String> = character.notes.iter().map(|n| n.value.to_string()).collect();
+        assert_eq!(notes, vec!["Owes the courier".to_string()]);
+
+        let mut current = Character::default();
+        for note in &["Owes the courier", ""] {
+            let (_, op) = current.notes.append(Text::new(note));
+            current.notes.apply(op);
         }
+        let json = serde_json::to_string(&current).unwrap();
+        assert_eq!(serde_json::from_str::<Character>(&json).unwrap(), current);
     }
 }
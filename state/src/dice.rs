@@ -1,58 +1,117 @@
+use crate::expression::{DiceExpression, Die, RollResult};
+use crate::Character;
+use aper::data_structures::{List, ListItem};
 use aper::{StateMachine, Transition};
-use rand::distributions::{Distribution, Uniform};
-use rand::SeedableRng;
-use rand_chacha::ChaCha12Rng;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::num::Wrapping;
+use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+/// The synced part of the dice. It only ever holds results; the random
+/// numbers themselves are produced by the server, see [`crate::GameProgram`].
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Dice {
-    pub rolls: Vec<u8>,
+    pub result: Option<RollResult>,
     pub last_rolled: Vec<bool>,
     /// used to show that dice have been rolled even if the result is
     /// the same as on the last roll.
     pub roll_id: Wrapping<u8>,
-    rng: ChaCha12Rng,
+    /// Who made the roll in `result`.
+    pub roller: Option<Roller>,
+    /// A roll that has been asked for but not yet resolved by the server.
+    pub pending: Option<PendingRoll>,
 }
 
-impl Default for Dice {
-    fn default() -> Self {
-        Self {
-            rolls: vec![],
-            last_rolled: vec![],
-            roll_id: Wrapping(0),
-            rng: ChaCha12Rng::from_entropy(),
+impl Dice {
+    pub fn rolls(&self) -> Vec<Die> {
+        self.result
+            .iter()
+            .flat_map(|r| r.dice().copied())
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingRoll {
+    pub request: RollRequest,
+    pub roller: Option<Roller>,
+    pub influence_spent: bool,
+    /// Hidden rolls are only shown to the GM, see [`crate::sealed::Sealed`].
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Roller {
+    Character(Uuid),
+    Player(String),
+}
+
+impl Roller {
+    /// A name to show for the roller. Characters are looked up by id, so a
+    /// renamed character shows its current name.
+    pub fn name(&self, characters: &List<Character>) -> String {
+        match self {
+            Roller::Character(char_id) => characters
+                .iter()
+                .find(|ListItem { id, .. }| id == char_id)
+                .map(|ListItem { value, .. }| value.name.to_string())
+                .unwrap_or_else(|| "a deleted character".to_string()),
+            Roller::Player(name) => name.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RollRequest {
+    Roll(DiceExpression),
+    Reroll(Vec<bool>),
+}
+
+impl RollRequest {
+    /// Rolls the dice needed to resolve this request.
+    pub fn resolve<R: Rng>(&self, previous: Option<&RollResult>, rng: &mut R) -> Option<RollResult> {
+        match self {
+            RollRequest::Roll(expression) => Some(expression.roll(rng)),
+            RollRequest::Reroll(mask) => previous.map(|p| p.reroll(mask, rng)),
         }
     }
 }
 
 #[derive(Transition, Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum DiceTransition {
-    Roll(u8),
-    Reroll(Vec<bool>),
+    Request(PendingRoll),
+    Resolve(RollResult),
+    /// Ends a hidden roll, whose result the dice never show.
+    ResolveHidden,
 }
 use DiceTransition::*;
 
 impl StateMachine for Dice {
     type Transition = DiceTransition;
     fn apply(&mut self, t: Self::Transition) {
-        let d4 = Uniform::from(1..=4);
         match t {
-            Roll(x) => {
-                self.rolls = (0..x).map(|_| d4.sample(&mut self.rng)).collect();
-                self.last_rolled = vec![true; x as usize];
-                self.roll_id += Wrapping(1);
+            Request(pending) => {
+                if self.pending.is_none() {
+                    self.pending = Some(pending);
+                }
+            }
+            Resolve(result) => {
+                if let Some(PendingRoll {
+                    request, roller, ..
+                }) = self.pending.take()
+                {
+                    self.last_rolled = match request {
+                        RollRequest::Roll(_) => vec![true; result.dice().count()],
+                        RollRequest::Reroll(mask) => mask,
+                    };
+                    self.result = Some(result);
+                    self.roller = roller;
+                    self.roll_id += Wrapping(1);
+                }
             }
-            Reroll(mask) => {
-                let rng = &mut self.rng;
-                self.rolls = self
-                    .rolls
-                    .iter()
-                    .zip(&mask)
-                    .map(|(r, m)| if *m { d4.sample(rng) } else { *r })
-                    .collect();
-                self.last_rolled = mask;
-                self.roll_id += Wrapping(1);
+            ResolveHidden => {
+                self.pending = None;
             }
         }
     }
@@ -0,0 +1,681 @@
+use rand::distributions::{Distribution, Uniform};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+const MAX_TERMS: usize = 20;
+const MAX_DICE: u8 = 100;
+const MAX_SIDES: u16 = 1000;
+const MAX_CONSTANT: i32 = 10_000;
+/// An exploding die stops exploding after this many extra dice.
+const MAX_EXPLOSIONS: usize = 100;
+
+/// A dice expression such as `3d4+2`, `2d6kh1`, `4d4!` or `d20 adv`.
+/// Letters may be written in either case.
+///
+/// Expressions travel as text, so a deserialized expression has gone
+/// through the same checks as one typed in by a player.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DiceExpression {
+    pub terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Term {
+    Dice(DiceTerm),
+    Constant(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DiceTerm {
+    pub negative: bool,
+    pub count: u8,
+    pub sides: u16,
+    pub explode: bool,
+    pub keep: Option<Keep>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Keep {
+    Highest(u8),
+    Lowest(u8),
+    DropHighest(u8),
+    DropLowest(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Empty,
+    Unexpected(char),
+    UnexpectedEnd,
+    NumberTooLarge,
+    TooManyTerms,
+    TooManyDice,
+    NoDice,
+    BadSides,
+    ExplodingD1,
+    KeepTooMany,
+    AdvantageNeedsOneDie,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ParseError::*;
+        match self {
+            Empty => write!(f, "the expression is empty"),
+            Unexpected(c) => write!(f, "unexpected '{}'", c),
+            UnexpectedEnd => write!(f, "the expression ends too early"),
+            NumberTooLarge => write!(f, "a number is too large"),
+            TooManyTerms => write!(f, "at most {} terms are allowed", MAX_TERMS),
+            TooManyDice => write!(f, "at most {} dice can be rolled at once", MAX_DICE),
+            NoDice => write!(f, "cannot roll zero dice"),
+            BadSides => write!(f, "dice must have between 1 and {} sides", MAX_SIDES),
+            ExplodingD1 => write!(f, "a one-sided die cannot explode"),
+            KeepTooMany => write!(f, "cannot keep or drop more dice than are rolled"),
+            AdvantageNeedsOneDie => write!(f, "adv and dis only apply to a single die"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl FromStr for DiceExpression {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            chars: s.chars().peekable(),
+        };
+        parser.skip_whitespace();
+        if parser.chars.peek().is_none() {
+            return Err(ParseError::Empty);
+        }
+
+        let mut terms = vec![parser.term(false)?];
+        loop {
+            parser.skip_whitespace();
+            let negative = match parser.chars.next() {
+                None => break,
+                Some('+') => false,
+                Some('-') => true,
+                Some(c) => return Err(ParseError::Unexpected(c)),
+            };
+            if terms.len() == MAX_TERMS {
+                return Err(ParseError::TooManyTerms);
+            }
+            parser.skip_whitespace();
+            terms.push(parser.term(negative)?);
+        }
+
+        Ok(Self { terms })
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn number(&mut self) -> Result<Option<u32>, ParseError> {
+        let mut n: Option<u32> = None;
+        while let Some(d) = self.chars.peek().and_then(|c| c.to_digit(10)) {
+            self.chars.next();
+            n = Some(
+                n.unwrap_or(0)
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add(d))
+                    .ok_or(ParseError::NumberTooLarge)?,
+            );
+        }
+        Ok(n)
+    }
+
+    fn required_number(&mut self) -> Result<u32, ParseError> {
+        match self.number()? {
+            Some(n) => Ok(n),
+            None => Err(self.unexpected()),
+        }
+    }
+
+    fn unexpected(&mut self) -> ParseError {
+        match self.chars.peek() {
+            Some(&c) => ParseError::Unexpected(c),
+            None => ParseError::UnexpectedEnd,
+        }
+    }
+
+    fn term(&mut self, negative: bool) -> Result<Term, ParseError> {
+        let count = self.number()?;
+        if !matches!(self.chars.peek(), Some('d') | Some('D')) {
+            let n = count.ok_or_else(|| self.unexpected())?;
+            if n > MAX_CONSTANT as u32 {
+                return Err(ParseError::NumberTooLarge);
+            }
+            let n = n as i32;
+            return Ok(Term::Constant(if negative { -n } else { n }));
+        }
+        self.chars.next();
+
+        let count = count.unwrap_or(1);
+        if count == 0 {
+            return Err(ParseError::NoDice);
+        }
+        if count > MAX_DICE as u32 {
+            return Err(ParseError::TooManyDice);
+        }
+        let sides = self.required_number()?;
+        if sides == 0 || sides > MAX_SIDES as u32 {
+            return Err(ParseError::BadSides);
+        }
+
+        let mut term = DiceTerm {
+            negative,
+            count: count as u8,
+            sides: sides as u16,
+            explode: false,
+            keep: None,
+        };
+
+        loop {
+            // `d20dis` is a die with disadvantage, not one dropping dice
+            let dis = self
+                .chars
+                .clone()
+                .take(3)
+                .collect::<String>()
+                .eq_ignore_ascii_case("dis");
+            match self.chars.peek() {
+                _ if dis => break,
+                Some('!') if !term.explode => {
+                    self.chars.next();
+                    term.explode = true;
+                }
+                Some('k') | Some('K') | Some('d') | Some('D') if term.keep.is_none() => {
+                    let drop = matches!(self.chars.next(), Some('d') | Some('D'));
+                    let highest = match self.chars.next() {
+                        Some('h') | Some('H') => true,
+                        Some('l') | Some('L') => false,
+                        Some(c) => return Err(ParseError::Unexpected(c)),
+                        None => return Err(ParseError::UnexpectedEnd),
+                    };
+                    let n = self.required_number()?;
+                    if n > term.count as u32 {
+                        return Err(ParseError::KeepTooMany);
+                    }
+                    let n = n as u8;
+                    term.keep = Some(match (drop, highest) {
+                        (false, true) => Keep::Highest(n),
+                        (false, false) => Keep::Lowest(n),
+                        (true, true) => Keep::DropHighest(n),
+                        (true, false) => Keep::DropLowest(n),
+                    });
+                }
+                _ => break,
+            }
+        }
+
+        // `adv` and `dis` may be separated from the die by spaces
+        let mut lookahead = self.chars.clone();
+        while lookahead.peek().map_or(false, |c| c.is_whitespace()) {
+            lookahead.next();
+        }
+        let word: String = lookahead
+            .clone()
+            .take_while(|c| c.is_ascii_alphabetic())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if word == "adv" || word == "dis" {
+            if term.count != 1 || term.keep.is_some() {
+                return Err(ParseError::AdvantageNeedsOneDie);
+            }
+            term.count = 2;
+            term.keep = Some(if word == "adv" {
+                Keep::Highest(1)
+            } else {
+                Keep::Lowest(1)
+            });
+            lookahead.nth(2);
+            self.chars = lookahead;
+        }
+
+        if term.explode && term.sides == 1 {
+            return Err(ParseError::ExplodingD1);
+        }
+
+        Ok(Term::Dice(term))
+    }
+}
+
+impl fmt::Display for DiceExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, term) in self.terms.iter().enumerate() {
+            let negative = match term {
+                Term::Dice(d) => d.negative,
+                Term::Constant(n) => *n < 0,
+            };
+            if negative {
+                write!(f, "-")?;
+            } else if i != 0 {
+                write!(f, "+")?;
+            }
+            match term {
+                Term::Dice(d) => {
+                    write!(f, "{}d{}", d.count, d.sides)?;
+                    if d.explode {
+                        write!(f, "!")?;
+                    }
+                    match d.keep {
+                        Some(Keep::Highest(n)) => write!(f, "kh{}", n)?,
+                        Some(Keep::Lowest(n)) => write!(f, "kl{}", n)?,
+                        Some(Keep::DropHighest(n)) => write!(f, "dh{}", n)?,
+                        Some(Keep::DropLowest(n)) => write!(f, "dl{}", n)?,
+                        None => {}
+                    }
+                }
+                Term::Constant(n) => write!(f, "{}", n.abs())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for DiceExpression {
+    type Error = ParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<DiceExpression> for String {
+    fn from(e: DiceExpression) -> Self {
+        e.to_string()
+    }
+}
+
+/// The outcome of rolling a [`DiceExpression`], term by term.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RollResult {
+    pub expression: DiceExpression,
+    pub terms: Vec<TermResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TermResult {
+    Dice(DiceTerm, Vec<Die>),
+    Constant(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Die {
+    pub sides: u16,
+    pub value: u16,
+    /// false if the die was dropped by a keep or drop modifier
+    pub kept: bool,
+    /// true if the die was added because the one before it exploded
+    pub exploded: bool,
+}
+
+impl DiceExpression {
+    pub fn roll<R: Rng>(&self, rng: &mut R) -> RollResult {
+        let terms = self
+            .terms
+            .iter()
+            .map(|term| match *term {
+                Term::Dice(d) => {
+                    let side = Uniform::from(1..=d.sides);
+                    let mut dice = vec![];
+                    for _ in 0..d.count {
+                        let mut value = side.sample(rng);
+                        dice.push(Die {
+                            sides: d.sides,
+                            value,
+                            kept: true,
+                            exploded: false,
+                        });
+                        let mut explosions = 0;
+                        while d.explode && value == d.sides && explosions < MAX_EXPLOSIONS {
+                            value = side.sample(rng);
+                            dice.push(Die {
+                                sides: d.sides,
+                                value,
+                                kept: true,
+                                exploded: true,
+                            });
+                            explosions += 1;
+                        }
+                    }
+                    apply_keep(&d, &mut dice);
+                    TermResult::Dice(d, dice)
+                }
+                Term::Constant(n) => TermResult::Constant(n),
+            })
+            .collect();
+
+        RollResult {
+            expression: self.clone(),
+            terms,
+        }
+    }
+}
+
+impl RollResult {
+    pub fn total(&self) -> i32 {
+        self.terms
+            .iter()
+            .map(|t| match t {
+                TermResult::Dice(term, dice) => {
+                    let sum: i32 = dice.iter().filter(|d| d.kept).map(|d| d.value as i32).sum();
+                    if term.negative {
+                        -sum
+                    } else {
+                        sum
+                    }
+                }
+                TermResult::Constant(n) => *n,
+            })
+            .sum()
+    }
+
+    /// Every die of every term, in order.
+    pub fn dice(&self) -> impl Iterator<Item = &Die> {
+        self.terms.iter().flat_map(|t| match t {
+            TermResult::Dice(_, dice) => dice.iter(),
+            TermResult::Constant(_) => [].iter(),
+        })
+    }
+
+    /// Rolls again the dice selected by `mask`, which is indexed like
+    /// [`RollResult::dice`]. Rerolled dice do not explode again, but keep
+    /// and drop modifiers are reapplied.
+    pub fn reroll<R: Rng>(&self, mask: &[bool], rng: &mut R) -> RollResult {
+        let mut mask = mask.iter().copied();
+        let terms = self
+            .terms
+            .iter()
+            .map(|t| match t {
+                TermResult::Dice(term, dice) => {
+                    let side = Uniform::from(1..=term.sides);
+                    let mut dice: Vec<Die> = dice
+                        .iter()
+                        .map(|&d| {
+                            if mask.next().unwrap_or(false) {
+                                Die {
+                                    value: side.sample(rng),
+                                    ..d
+                                }
+                            } else {
+                                d
+                            }
+                        })
+                        .collect();
+                    apply_keep(term, &mut dice);
+                    TermResult::Dice(*term, dice)
+                }
+                TermResult::Constant(n) => TermResult::Constant(*n),
+            })
+            .collect();
+
+        RollResult {
+            expression: self.expression.clone(),
+            terms,
+        }
+    }
+}
+
+fn apply_keep(term: &DiceTerm, dice: &mut [Die]) {
+    let mut order: Vec<usize> = (0..dice.len()).collect();
+    order.sort_by_key(|&i| dice[i].value);
+
+    let dropped = match term.keep {
+        None => 0..0,
+        Some(Keep::Highest(n)) => 0..dice.len().saturating_sub(n as usize),
+        Some(Keep::Lowest(n)) => (n as usize).min(dice.len())..dice.len(),
+        Some(Keep::DropHighest(n)) => dice.len().saturating_sub(n as usize)..dice.len(),
+        Some(Keep::DropLowest(n)) => 0..(n as usize).min(dice.len()),
+    };
+    for (rank, &i) in order.iter().enumerate() {
+        dice[i].kept = !dropped.contains(&rank);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha12Rng;
+
+    fn parse(s: &str) -> DiceExpression {
+        s.parse().unwrap()
+    }
+
+    fn dice(count: u8, sides: u16) -> DiceTerm {
+        DiceTerm {
+            negative: false,
+            count,
+            sides,
+            explode: false,
+            keep: None,
+        }
+    }
+
+    #[test]
+    fn parses_dice_and_constants() {
+        assert_eq!(
+            parse("3d4+2").terms,
+            vec![Term::Dice(dice(3, 4)), Term::Constant(2)]
+        );
+        assert_eq!(
+            parse(" d6 - 1d8 - 3 ").terms,
+            vec![
+                Term::Dice(dice(1, 6)),
+                Term::Dice(DiceTerm {
+                    negative: true,
+                    ..dice(1, 8)
+                }),
+                Term::Constant(-3),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_keep_and_drop() {
+        let keep = |s| match parse(s).terms[0] {
+            Term::Dice(d) => d.keep,
+            _ => panic!("{} is not dice", s),
+        };
+        assert_eq!(keep("2d6kh1"), Some(Keep::Highest(1)));
+        assert_eq!(keep("4d6kl3"), Some(Keep::Lowest(3)));
+        assert_eq!(keep("4d6dh1"), Some(Keep::DropHighest(1)));
+        assert_eq!(keep("4d6dl1"), Some(Keep::DropLowest(1)));
+    }
+
+    #[test]
+    fn parses_exploding_dice() {
+        assert_eq!(
+            parse("4d4!").terms,
+            vec![Term::Dice(DiceTerm {
+                explode: true,
+                ..dice(4, 4)
+            })]
+        );
+    }
+
+    #[test]
+    fn parses_advantage() {
+        let keep = |k| {
+            vec![Term::Dice(DiceTerm {
+                keep: Some(k),
+                ..dice(2, 20)
+            })]
+        };
+        assert_eq!(parse("d20 adv").terms, keep(Keep::Highest(1)));
+        assert_eq!(parse("1d20dis").terms, keep(Keep::Lowest(1)));
+        assert_eq!(parse("d20 adv + 3").terms.len(), 2);
+    }
+
+    #[test]
+    fn letters_are_case_insensitive() {
+        assert_eq!(parse("2D6KH1"), parse("2d6kh1"));
+        assert_eq!(parse("4d6DL1"), parse("4d6dl1"));
+        assert_eq!(parse("4D6kL3"), parse("4d6kl3"));
+        assert_eq!(parse("D20 ADV"), parse("d20 adv"));
+        assert_eq!(parse("d20 Dis"), parse("d20 dis"));
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for s in &[
+            "3d4+2",
+            "2d6kh1",
+            "4d4!",
+            "d20 adv",
+            "d20 dis",
+            "4d6dl1-1d4+10",
+            "100d1000!dh99",
+        ] {
+            let expression = parse(s);
+            assert_eq!(parse(&expression.to_string()), expression, "{}", s);
+
+            let json = serde_json::to_string(&expression).unwrap();
+            let back: DiceExpression = serde_json::from_str(&json).unwrap();
+            assert_eq!(back, expression, "{}", s);
+        }
+        assert_eq!(parse("2d6kh1").to_string(), "2d6kh1");
+        assert_eq!(parse("d20 adv").to_string(), "2d20kh1");
+        assert_eq!(parse("3 - 2").to_string(), "3-2");
+    }
+
+    #[test]
+    fn deserializing_checks_the_expression() {
+        assert!(serde_json::from_str::<DiceExpression>("\"0d6\"").is_err());
+        assert!(serde_json::from_str::<DiceExpression>("\"1d6+\"").is_err());
+    }
+
+    #[test]
+    fn reports_each_error() {
+        let error = |s: &str| s.parse::<DiceExpression>().unwrap_err();
+        assert_eq!(error(""), ParseError::Empty);
+        assert_eq!(error("   "), ParseError::Empty);
+        assert_eq!(error("3x4"), ParseError::Unexpected('x'));
+        assert_eq!(error("2d6kx1"), ParseError::Unexpected('x'));
+        assert_eq!(error("3d"), ParseError::UnexpectedEnd);
+        assert_eq!(error("3d6+"), ParseError::UnexpectedEnd);
+        assert_eq!(error("2d6k"), ParseError::UnexpectedEnd);
+        assert_eq!(error("10001"), ParseError::NumberTooLarge);
+        assert_eq!(error("99999999999d6"), ParseError::NumberTooLarge);
+        assert_eq!(
+            error(&vec!["1"; MAX_TERMS + 1].join("+")),
+            ParseError::TooManyTerms
+        );
+        assert!(vec!["1"; MAX_TERMS]
+            .join("+")
+            .parse::<DiceExpression>()
+            .is_ok());
+        assert_eq!(error("101d6"), ParseError::TooManyDice);
+        assert_eq!(error("0d6"), ParseError::NoDice);
+        assert_eq!(error("1d0"), ParseError::BadSides);
+        assert_eq!(error("1d1001"), ParseError::BadSides);
+        assert_eq!(error("1d1!"), ParseError::ExplodingD1);
+        assert_eq!(error("2d6kh3"), ParseError::KeepTooMany);
+        assert_eq!(error("2d20 adv"), ParseError::AdvantageNeedsOneDie);
+        assert_eq!(error("d20kh1 dis"), ParseError::AdvantageNeedsOneDie);
+    }
+
+    #[test]
+    fn totals_kept_dice_and_constants() {
+        for seed in 0..100 {
+            let result = parse("3d4+2").roll(&mut ChaCha12Rng::seed_from_u64(seed));
+            let dice: Vec<_> = result.dice().collect();
+            assert_eq!(dice.len(), 3);
+            assert!(dice.iter().all(|d| (1..=4).contains(&d.value) && d.kept));
+            let sum: i32 = dice.iter().map(|d| d.value as i32).sum();
+            assert_eq!(result.total(), sum + 2);
+
+            let result = parse("2d6kh1-1d4").roll(&mut ChaCha12Rng::seed_from_u64(seed));
+            let values: Vec<i32> = result.dice().map(|d| d.value as i32).collect();
+            assert_eq!(result.total(), values[0].max(values[1]) - values[2]);
+        }
+    }
+
+    #[test]
+    fn same_seed_rolls_the_same() {
+        let expression = parse("4d6dl1+1d20!");
+        let a = expression.roll(&mut ChaCha12Rng::seed_from_u64(7));
+        let b = expression.roll(&mut ChaCha12Rng::seed_from_u64(7));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn exploding_dice_roll_again_on_the_highest_side() {
+        for seed in 0..100 {
+            let result = parse("4d4!").roll(&mut ChaCha12Rng::seed_from_u64(seed));
+            let dice: Vec<_> = result.dice().collect();
+            assert_eq!(dice.iter().filter(|d| !d.exploded).count(), 4);
+            for pair in dice.windows(2) {
+                assert_eq!(pair[1].exploded, pair[0].value == 4);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_and_drops_by_value() {
+        let kept = |keep| {
+            let mut rolled: Vec<Die> = [3, 1, 6, 4]
+                .iter()
+                .map(|&value| Die {
+                    sides: 6,
+                    value,
+                    kept: true,
+                    exploded: false,
+                })
+                .collect();
+            apply_keep(&DiceTerm { keep, ..dice(4, 6) }, &mut rolled);
+            rolled.iter().map(|d| d.kept).collect::<Vec<_>>()
+        };
+        assert_eq!(kept(None), vec![true, true, true, true]);
+        assert_eq!(kept(Some(Keep::Highest(2))), vec![false, false, true, true]);
+        assert_eq!(kept(Some(Keep::Lowest(1))), vec![false, true, false, false]);
+        assert_eq!(
+            kept(Some(Keep::DropHighest(1))),
+            vec![true, true, false, true]
+        );
+        assert_eq!(
+            kept(Some(Keep::DropLowest(2))),
+            vec![false, false, true, true]
+        );
+        // Exploded dice can make a term longer than its keep count
+        assert_eq!(kept(Some(Keep::Highest(4))), vec![true, true, true, true]);
+        assert_eq!(
+            kept(Some(Keep::Lowest(0))),
+            vec![false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn rerolls_only_masked_dice_and_keeps_again() {
+        let expression = parse("3d6kh2");
+        let mut rng = ChaCha12Rng::seed_from_u64(3);
+        let first = expression.roll(&mut rng);
+        let second = first.reroll(&[false, true, false], &mut rng);
+
+        let before: Vec<_> = first.dice().collect();
+        let after: Vec<_> = second.dice().collect();
+        assert_eq!(before[0].value, after[0].value);
+        assert_eq!(before[2].value, after[2].value);
+        assert_eq!(after.iter().filter(|d| d.kept).count(), 2);
+        let lowest = after.iter().map(|d| d.value).min().unwrap() as i32;
+        let sum: i32 = after.iter().map(|d| d.value as i32).sum();
+        assert_eq!(second.total(), sum - lowest);
+    }
+}
@@ -0,0 +1,28 @@
+use crate::dice::Roller;
+use crate::expression::{DiceExpression, RollResult};
+use crate::sealed::Sealed;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// One entry of the roll log kept in [`crate::Game`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RollRecord {
+    pub expression: DiceExpression,
+    /// `None` for a hidden roll until it is revealed.
+    pub result: Option<RollResult>,
+    /// `None` for a fresh roll, otherwise the dice that were rolled again.
+    pub rerolled: Option<Vec<bool>>,
+    pub roller: Option<Roller>,
+    /// Set by the server when it resolved the roll.
+    pub time: DateTime<Utc>,
+    pub influence_spent: bool,
+    /// The result of a hidden roll, readable by the GM who made it.
+    #[serde(default)]
+    pub sealed: Option<Sealed>,
+    /// The GM has asked the server to reveal this hidden roll.
+    #[serde(default)]
+    pub reveal_requested: bool,
+    /// When a hidden roll was revealed.
+    #[serde(default)]
+    pub revealed: Option<DateTime<Utc>>,
+}
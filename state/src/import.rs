@@ -0,0 +1,166 @@
+use crate::Character;
+use aper::data_structures::{List, ListItem, ListOperation};
+use aper::StateMachine;
+
+/// What to do with a loaded character that is already in the game, either
+/// with the same ID or the same name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conflict {
+    KeepBoth,
+    Overwrite,
+    Skip,
+}
+
+/// Builds the operations that add `incoming` to `current`, for
+/// [`crate::GameTransition::Import`].
+///
+/// New IDs are picked here rather than when the import is applied, so that
+/// every copy of the game ends up with the same ones. An overwritten
+/// character takes the place of the one it replaces.
+pub fn import_characters(
+    current: &List<Character>,
+    incoming: &List<Character>,
+    conflict: Conflict,
+) -> Vec<ListOperation<Character>> {
+    let mut working = current.clone();
+    let mut operations = Vec::new();
+    let mut push = |working: &mut List<Character>, op: ListOperation<Character>| {
+        working.apply(op.clone());
+        operations.push(op);
+    };
+
+    // Characters this import added, which later ones don't conflict with
+    let mut added = Vec::new();
+
+    for ListItem { id, value, .. } in incoming.iter() {
+        // Looked up in `working`, so that a character already overwritten
+        // can't be overwritten again
+        let existing = working
+            .iter()
+            .find(|c| c.id == id && !added.contains(&c.id))
+            .or_else(|| {
+                let name = value.name.to_string();
+                working
+                    .iter()
+                    .find(|c| !added.contains(&c.id) && c.value.name.to_string() == name)
+            })
+            .map(|c| (c.id, c.location.clone()));
+
+        match (existing, conflict) {
+            (Some(_), Conflict::Skip) => {}
+            (Some((existing_id, location)), Conflict::Overwrite) => {
+                let (new_id, op) = working.append(value.clone());
+                push(&mut working, op);
+                let op = working.delete(existing_id);
+                push(&mut working, op);
+                push(&mut working, ListOperation::Move(new_id, location));
+                added.push(new_id);
+            }
+            (None, _) | (Some(_), Conflict::KeepBoth) => {
+                let (new_id, op) = working.append(value.clone());
+                push(&mut working, op);
+                added.push(new_id);
+            }
+        }
+    }
+    operations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::Text;
+    use uuid::Uuid;
+
+    fn list(characters: &[(&str, &str)]) -> List<Character> {
+        let mut list = List::new();
+        for (name, habitat) in characters {
+            let (_, op) = list.append(Character {
+                name: Text::new(name),
+                habitat: Text::new(habitat),
+                ..Character::default()
+            });
+            list.apply(op);
+        }
+        list
+    }
+
+    /// The names and habitats of the characters after an import.
+    fn imported(
+        current: &List<Character>,
+        incoming: &List<Character>,
+        conflict: Conflict,
+    ) -> Vec<(String, String)> {
+        let mut game = current.clone();
+        for op in import_characters(current, incoming, conflict) {
+            game.apply(op);
+        }
+        game.iter()
+            .map(|c| (c.value.name.to_string(), c.value.habitat.to_string()))
+            .collect()
+    }
+
+    fn pairs(characters: &[(&str, &str)]) -> Vec<(String, String)> {
+        characters
+            .iter()
+            .map(|(name, habitat)| (name.to_string(), habitat.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn keep_both_adds_everything() {
+        let current = list(&[("Ada", "here"), ("Bo", "here")]);
+        let incoming = list(&[("Ada", "there"), ("Ada", "elsewhere")]);
+        assert_eq!(
+            imported(&current, &incoming, Conflict::KeepBoth),
+            pairs(&[
+                ("Ada", "here"),
+                ("Bo", "here"),
+                ("Ada", "there"),
+                ("Ada", "elsewhere"),
+            ])
+        );
+    }
+
+    #[test]
+    fn overwrite_replaces_in_place_once() {
+        let current = list(&[("Ada", "here"), ("Bo", "here")]);
+        // The second Ada matches nothing left, as the first replaced the
+        // only one and characters added by the import don't count
+        let incoming = list(&[("Ada", "there"), ("Ada", "elsewhere")]);
+        assert_eq!(
+            imported(&current, &incoming, Conflict::Overwrite),
+            pairs(&[("Ada", "there"), ("Bo", "here"), ("Ada", "elsewhere")])
+        );
+    }
+
+    #[test]
+    fn skip_leaves_matching_characters_alone() {
+        let current = list(&[("Ada", "here")]);
+        let incoming = list(&[("Ada", "there"), ("Cy", "there"), ("Cy", "elsewhere")]);
+        assert_eq!(
+            imported(&current, &incoming, Conflict::Skip),
+            pairs(&[("Ada", "here"), ("Cy", "there"), ("Cy", "elsewhere")])
+        );
+    }
+
+    #[test]
+    fn characters_are_matched_by_id_too() {
+        let current = list(&[("Ada", "here")]);
+        let mut incoming = current.clone();
+        let id = incoming.iter().next().unwrap().id;
+        let rename = incoming.map_item(id, |c| {
+            c.map_name(|n| n.edit("Ada Lovelace", Uuid::from_u128(1)))
+        });
+        incoming.apply(rename);
+
+        assert_eq!(
+            imported(&current, &incoming, Conflict::Overwrite),
+            pairs(&[("Ada Lovelace", "here")])
+        );
+        assert_eq!(
+            imported(&current, &incoming, Conflict::Skip),
+            pairs(&[("Ada", "here")])
+        );
+    }
+}
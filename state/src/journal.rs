@@ -0,0 +1,60 @@
+use crate::player::PlayerKey;
+use crate::{Game, GameTransition};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// One transition applied to a room, as the server saw it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JournalEntry {
+    pub time: DateTime<Utc>,
+    /// `None` for the server's own transitions, such as roll results.
+    pub author: Option<PlayerKey>,
+    /// The author's name at the time, for people reading the journal.
+    pub name: Option<String>,
+    pub transition: GameTransition,
+}
+
+/// A journal is a game followed by everything that happened to it, one
+/// line of JSON each, so it can be replayed without any other file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum JournalLine {
+    Start(Game),
+    Applied(JournalEntry),
+}
+
+impl JournalLine {
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+/// Reads a journal. A last line cut short by a crash is left out.
+pub fn parse(text: &str) -> Vec<JournalLine> {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        match serde_json::from_str(line) {
+            Ok(line) => lines.push(line),
+            Err(_) => break,
+        }
+    }
+    lines
+}
+
+/// Rebuilds a game by applying every entry the way the server did. Returns
+/// `None` if the journal doesn't start with a game.
+pub fn replay(lines: impl IntoIterator<Item = JournalLine>) -> Option<Game> {
+    let mut lines = lines.into_iter();
+    let mut game = match lines.next()? {
+        JournalLine::Start(game) => game,
+        JournalLine::Applied(_) => return None,
+    };
+    for line in lines {
+        match line {
+            JournalLine::Start(start) => game = start,
+            JournalLine::Applied(entry) => game.apply_as(entry.author, entry.transition),
+        }
+    }
+    Some(game)
+}
@@ -1,42 +1,158 @@
 pub mod character;
 pub mod dice;
+pub mod expression;
+pub mod history;
+pub mod import;
+pub mod journal;
+pub mod list;
+pub mod player;
+mod program;
+pub mod save;
+pub mod sealed;
+pub mod stat;
+pub mod text;
+pub mod undo;
 
-use aper::data_structures::List;
+use aper::data_structures::{List, ListOperation};
 use aper::{StateMachine, Transition};
 pub use character::Character;
-use dice::Dice;
+use chrono::{DateTime, Utc};
+use dice::{Dice, PendingRoll, RollRequest, Roller};
+use expression::{DiceExpression, RollResult};
+use history::RollRecord;
+use player::PlayerKey;
+use sealed::{Sealed, SealedPassword};
+use undo::{Snapshot, Step, UndoStack};
+pub use program::{
+    valid_room_name, GameProgram, ProgramTransition, Room, RoomStore, HEARTBEAT_SECONDS,
+    PRESENT_SECONDS,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use uuid::Uuid;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Game {
     pub dice: Dice,
     pub characters: List<Character>,
+    /// Every resolved roll, oldest first. Only ever appended to.
+    pub log: Vec<RollRecord>,
+    /// Who may edit each character. Characters without an owner are open to
+    /// everybody.
+    #[serde(default)]
+    pub owners: BTreeMap<Uuid, PlayerKey>,
+    /// The game master may edit every character and use the privileged
+    /// transitions. A room without a GM lets everybody use them.
+    #[serde(default)]
+    pub gm: Option<PlayerKey>,
+    /// Players whose transitions this room ignores.
+    #[serde(default)]
+    pub kicked: BTreeSet<PlayerKey>,
+    /// Players who have changed something here or were let in by the GM.
+    #[serde(default)]
+    pub members: BTreeSet<PlayerKey>,
+    /// Set once the GM kicks somebody, see [`Game::admits`], and lifted
+    /// when the GM steps down.
+    #[serde(default)]
+    pub locked: bool,
+    /// What each player can take back of their changes to the characters.
+    #[serde(default)]
+    pub undo: BTreeMap<PlayerKey, UndoStack>,
+    /// Set on games saved while anyone could make up a player key, see
+    /// [`Game::forget_legacy_keys`].
+    #[serde(default = "saved_with_legacy_keys")]
+    pub legacy_keys: bool,
+}
+
+fn saved_with_legacy_keys() -> bool {
+    true
 }
 
 #[derive(Transition, Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum GameTransition {
-    Roll(u8),
+    Roll(DiceExpression, Option<Roller>),
     Reroll(Vec<bool>, Uuid),
+    /// The outcome of the pending roll. Only accepted from the server.
+    Resolve(RollResult, DateTime<Utc>),
+    /// A roll only the GM sees the result of.
+    HiddenRoll(DiceExpression),
+    /// The outcome of a pending hidden roll, or `None` if there is nobody
+    /// left to seal it for and the roll is called off. Only accepted from
+    /// the server.
+    ResolveHidden(Option<Sealed>, DateTime<Utc>),
+    /// Asks the server to reveal the hidden roll at this index of the log.
+    Reveal(usize),
+    /// Only accepted from the server.
+    Revealed(usize, RollResult, DateTime<Utc>),
     CharacterTransition(<List<Character> as StateMachine>::Transition),
     Load(List<Character>),
+    /// Replaces everything but who the GM is, from a save.
+    Restore(Game),
+    /// Character operations applied together, see
+    /// [`import::import_characters`].
+    Import(Vec<<List<Character> as StateMachine>::Transition>),
+    /// Takes back the player's last change to the characters.
+    Undo,
+    Redo,
+    /// Asks the server to check the room's GM password, which is kept by
+    /// the server alone. The first claim in a room sets it.
+    ClaimGm(SealedPassword),
+    /// The password was right. Only accepted from the server.
+    GmClaimed(PlayerKey),
+    ReleaseGm,
+    ClearLog,
+    /// Also locks the room to players it hasn't heard from yet.
+    Kick(PlayerKey),
+    /// Lets a player in, whether they were kicked or are new to a locked
+    /// room.
+    Unkick(PlayerKey),
 }
 use GameTransition::*;
 
+impl GameTransition {
+    /// Transitions that carry dice results or decide who the GM is, which
+    /// only the server may make.
+    pub fn server_only(&self) -> bool {
+        matches!(
+            self,
+            Resolve(..) | ResolveHidden(..) | Revealed(..) | GmClaimed(_)
+        )
+    }
+}
+
 impl StateMachine for Game {
     type Transition = GameTransition;
 
     fn apply(&mut self, transition: Self::Transition) {
         match transition {
-            Roll(x) => self.dice.apply(dice::DiceTransition::Roll(x)),
+            Roll(x, roller) => self.dice.apply(dice::DiceTransition::Request(PendingRoll {
+                request: RollRequest::Roll(x),
+                roller,
+                influence_spent: false,
+                hidden: false,
+            })),
+            HiddenRoll(x) => self.dice.apply(dice::DiceTransition::Request(PendingRoll {
+                request: RollRequest::Roll(x),
+                roller: None,
+                influence_spent: false,
+                hidden: true,
+            })),
             Reroll(mask, character) => {
+                if self.dice.pending.is_some() || self.dice.result.is_none() {
+                    return;
+                }
                 let dice = &mut self.dice;
                 self.characters
                     .apply(self.characters.map_item(character, |c| {
                         c.map_influence_points(|i| {
                             let old = *i.value();
                             if old > 0 {
-                                dice.apply(dice::DiceTransition::Reroll(mask));
+                                dice.apply(dice::DiceTransition::Request(PendingRoll {
+                                    request: RollRequest::Reroll(mask),
+                                    roller: Some(Roller::Character(character)),
+                                    influence_spent: true,
+                                    hidden: false,
+                                }));
                                 i.replace(old - 1)
                             } else {
                                 i.replace(old)
@@ -44,8 +160,443 @@ impl StateMachine for Game {
                         })
                     }));
             }
+            Resolve(result, time) => match &self.dice.pending {
+                Some(pending) if !pending.hidden => {
+                    self.log.push(RollRecord {
+                        expression: result.expression.clone(),
+                        result: Some(result.clone()),
+                        rerolled: match &pending.request {
+                            RollRequest::Roll(_) => None,
+                            RollRequest::Reroll(mask) => Some(mask.clone()),
+                        },
+                        roller: pending.roller.clone(),
+                        time,
+                        influence_spent: pending.influence_spent,
+                        sealed: None,
+                        reveal_requested: false,
+                        revealed: None,
+                    });
+                    self.dice.apply(dice::DiceTransition::Resolve(result))
+                }
+                _ => {}
+            },
+            ResolveHidden(sealed, time) => {
+                if let Some(PendingRoll {
+                    request: RollRequest::Roll(expression),
+                    hidden: true,
+                    ..
+                }) = &self.dice.pending
+                {
+                    if let Some(sealed) = sealed {
+                        self.log.push(RollRecord {
+                            expression: expression.clone(),
+                            result: None,
+                            rerolled: None,
+                            roller: None,
+                            time,
+                            influence_spent: false,
+                            sealed: Some(sealed),
+                            reveal_requested: false,
+                            revealed: None,
+                        });
+                    }
+                    self.dice.apply(dice::DiceTransition::ResolveHidden)
+                }
+            }
+            Reveal(i) => {
+                if let Some(record) = self.log.get_mut(i) {
+                    if record.sealed.is_some() && record.result.is_none() {
+                        record.reveal_requested = true;
+                    }
+                }
+            }
+            Revealed(i, result, time) => {
+                if let Some(record) = self.log.get_mut(i) {
+                    if record.reveal_requested {
+                        record.result = Some(result);
+                        record.revealed = Some(time);
+                        record.reveal_requested = false;
+                    }
+                }
+            }
             CharacterTransition(t) => self.characters.apply(t),
             Load(x) => self.characters = x,
+            Restore(mut game) => {
+                game.forget_legacy_keys();
+                *self = Game {
+                    gm: self.gm,
+                    undo: std::mem::take(&mut self.undo),
+                    ..game
+                }
+            }
+            Import(operations) => {
+                for op in operations {
+                    self.characters.apply(op);
+                }
+            }
+            // These need to know who is asking, see `apply_as`
+            Undo | Redo => {}
+            // Checked by the server, which answers with `GmClaimed`
+            ClaimGm(_) => {}
+            GmClaimed(player) => self.gm = Some(player),
+            ReleaseGm => {
+                self.gm = None;
+                // Nobody is left to let newcomers in
+                self.locked = false;
+            }
+            ClearLog => self.log.clear(),
+            Kick(player) => {
+                self.kicked.insert(player);
+                self.members.remove(&player);
+                self.locked = true;
+            }
+            Unkick(player) => {
+                self.kicked.remove(&player);
+                self.members.insert(player);
+            }
+        }
+    }
+}
+
+impl Game {
+    /// Player keys used to be hashes of a token every client was sent, so
+    /// a game saved back then forgets who owned, ran and was kicked from it.
+    pub fn forget_legacy_keys(&mut self) {
+        if std::mem::take(&mut self.legacy_keys) {
+            self.owners.clear();
+            self.gm = None;
+            self.kicked.clear();
+            self.members.clear();
+            self.locked = false;
+            self.undo.clear();
+        }
+    }
+
+    /// Whether transitions from `actor` count in this room. Once somebody
+    /// has been kicked, players the room hasn't heard from need to be let
+    /// in, so a kicked player can't come back with a new key.
+    pub fn admits(&self, actor: PlayerKey) -> bool {
+        !self.kicked.contains(&actor)
+            && (!self.locked || self.members.contains(&actor) || self.gm == Some(actor))
+    }
+
+    pub fn is_gm(&self, actor: Option<PlayerKey>) -> bool {
+        match self.gm {
+            None => true,
+            Some(gm) => actor == Some(gm),
+        }
+    }
+
+    pub fn may_edit(&self, actor: Option<PlayerKey>, character: Uuid) -> bool {
+        if self.gm.is_some() && self.is_gm(actor) {
+            return true;
+        }
+        match self.owners.get(&character) {
+            None => true,
+            Some(owner) => actor == Some(*owner),
+        }
+    }
+
+    /// Applies a transition on behalf of `actor`, ignoring it if `actor`
+    /// isn't allowed to make it. Players may only touch and roll for
+    /// characters they own and may not give themselves points while there is
+    /// a GM. Characters `actor` creates are theirs.
+    pub fn apply_as(&mut self, actor: Option<PlayerKey>, transition: GameTransition) {
+        if let Some(actor) = actor {
+            if !self.admits(actor) {
+                return;
+            }
+            self.members.insert(actor);
+        }
+
+        // Only the characters a transition touches are copied for undo
+        let touched = self.touched_by(&transition);
+        let before = undo::snapshot_of(&self.characters, &self.owners, &touched);
+
+        match &transition {
+            Undo | Redo => {
+                if let Some(actor) = actor {
+                    self.undo_redo(actor, transition == Undo);
+                }
+            }
+            Load(_) | Restore(_) | ReleaseGm | ClearLog if !self.is_gm(actor) => {}
+            // Hiding a roll only makes sense from an actual GM, and without
+            // one nobody could undo a kick
+            HiddenRoll(_) | Reveal(_) | Kick(_) | Unkick(_)
+                if self.gm.is_none() || !self.is_gm(actor) => {}
+            Reroll(_, character) if !self.may_edit(actor, *character) => {}
+            Roll(_, Some(Roller::Character(character))) if !self.may_edit(actor, *character) => {}
+            CharacterTransition(t) => {
+                self.apply_characters(actor, std::slice::from_ref(t), &touched, &before)
+            }
+            Import(operations) => self.apply_characters(actor, operations, &touched, &before),
+            _ => self.apply(transition),
+        }
+
+        if let Some(actor) = actor.filter(|_| !touched.is_empty()) {
+            let after = undo::snapshot_of(&self.characters, &self.owners, &touched);
+            if let Some(step) = Step::between(&before, &after) {
+                self.undo.entry(actor).or_default().push(step);
+            }
+        }
+    }
+
+    fn undo_redo(&mut self, actor: PlayerKey, undo: bool) {
+        let stack = match self.undo.get(&actor) {
+            Some(stack) => stack,
+            None => return,
+        };
+        let step = if undo { stack.next_undo() } else { stack.next_redo() };
+        // Ownership may have changed since
+        if !step.map_or(false, |s| s.characters().all(|id| self.may_edit(Some(actor), id))) {
+            return;
+        }
+
+        let stack = self.undo.get_mut(&actor).unwrap();
+        if undo {
+            stack.undo(&mut self.characters, &mut self.owners);
+        } else {
+            stack.redo(&mut self.characters, &mut self.owners);
+        }
+    }
+
+    /// The characters a transition may change, which are the ones undo
+    /// has to keep track of.
+    fn touched_by(&self, transition: &GameTransition) -> BTreeSet<Uuid> {
+        let ids_of = |characters: &List<Character>| {
+            self.characters
+                .iter()
+                .chain(characters.iter())
+                .map(|c| c.id)
+                .collect()
+        };
+        match transition {
+            CharacterTransition(op) => std::iter::once(operation_target(op)).collect(),
+            Import(operations) => operations.iter().map(operation_target).collect(),
+            Load(characters) => ids_of(characters),
+            Restore(game) => ids_of(&game.characters),
+            _ => BTreeSet::new(),
         }
     }
+
+    /// Applies list operations to the characters, or takes them back if
+    /// `actor` may not make them. `before` are the `touched` characters as
+    /// they were.
+    fn apply_characters(
+        &mut self,
+        actor: Option<PlayerKey>,
+        operations: &[<List<Character> as StateMachine>::Transition],
+        touched: &BTreeSet<Uuid>,
+        before: &BTreeMap<Uuid, Snapshot>,
+    ) {
+        for op in operations {
+            self.characters.apply(op.clone());
+        }
+
+        // Diffing is simpler than taking every kind of list operation apart.
+        // New characters may not start with more points than a new sheet.
+        let restricted = self.gm.is_some() && !self.is_gm(actor);
+        let new_sheet = Character::default();
+        let not_allowed = touched.iter().any(|&id| {
+            let was = before.get(&id).map(|s| &s.character);
+            let now = self.characters.iter().find(|c| c.id == id).map(|c| c.value);
+            let most = was.unwrap_or(&new_sheet);
+            let gained_points = now.map_or(false, |now| {
+                now.influence_points.value() > most.influence_points.value()
+                    || now.memory_points.value() > most.memory_points.value()
+            });
+            (was != now && !self.may_edit(actor, id)) || (gained_points && restricted)
+        });
+        if not_allowed {
+            let after = undo::snapshot_of(&self.characters, &self.owners, touched);
+            if let Some(step) = Step::between(before, &after) {
+                step.put_back(&mut self.characters, &mut self.owners, true);
+            }
+            return;
+        }
+
+        for &id in touched {
+            let exists = self.characters.iter().any(|c| c.id == id);
+            match (before.contains_key(&id), exists, actor) {
+                (false, true, Some(actor)) => {
+                    self.owners.insert(id, actor);
+                }
+                (true, false, _) => {
+                    self.owners.remove(&id);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The character a list operation is about.
+fn operation_target(op: &<List<Character> as StateMachine>::Transition) -> Uuid {
+    match op {
+        ListOperation::Insert(_, id, _)
+        | ListOperation::Delete(id)
+        | ListOperation::Move(id, _)
+        | ListOperation::Apply(id, _) => *id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use import::{import_characters, Conflict};
+
+    const ALICE: PlayerKey = PlayerKey(1);
+    const BOB: PlayerKey = PlayerKey(2);
+    const GM: PlayerKey = PlayerKey(3);
+
+    /// A game with one character, added by `owner`.
+    fn game_with_character(owner: PlayerKey) -> (Game, Uuid) {
+        let mut game = Game::default();
+        let (id, op) = game.characters.append(Character::default());
+        game.apply_as(Some(owner), CharacterTransition(op));
+        (game, id)
+    }
+
+    fn type_name(game: &mut Game, actor: PlayerKey, id: Uuid, name: &str) {
+        let op = game.characters.map_item(id, |c| {
+            c.map_name(|n| n.edit(name, Uuid::new_v4()))
+        });
+        game.apply_as(Some(actor), CharacterTransition(op));
+    }
+
+    fn name(game: &Game, id: Uuid) -> String {
+        let character = game.characters.iter().find(|c| c.id == id).unwrap();
+        character.value.name.to_string()
+    }
+
+    #[test]
+    fn typing_into_one_field_is_undone_at_once() {
+        let (mut game, id) = game_with_character(ALICE);
+        for name in &["A", "Ad", "Ada"] {
+            type_name(&mut game, ALICE, id, name);
+        }
+        let op = game
+            .characters
+            .map_item(id, |c| c.map_habitat(|h| h.edit("Lowtown", Uuid::new_v4())));
+        game.apply_as(Some(ALICE), CharacterTransition(op));
+        type_name(&mut game, ALICE, id, "Adam");
+
+        game.apply_as(Some(ALICE), Undo);
+        assert_eq!(name(&game, id), "Ada");
+        game.apply_as(Some(ALICE), Undo);
+        assert_eq!(name(&game, id), "Ada");
+        game.apply_as(Some(ALICE), Undo);
+        assert_eq!(name(&game, id), "");
+        assert_eq!(game.characters.iter().count(), 1);
+    }
+
+    #[test]
+    fn players_cannot_add_characters_with_extra_points_under_a_gm() {
+        let (mut game, id) = game_with_character(ALICE);
+        game.apply(GmClaimed(GM));
+
+        let pumped = Character {
+            influence_points: character::Points::new(9),
+            ..Character::default()
+        };
+        let (_, op) = game.characters.append(pumped.clone());
+        game.apply_as(Some(ALICE), CharacterTransition(op));
+        let mut incoming = List::new();
+        let (_, op) = incoming.append(pumped);
+        incoming.apply(op);
+        for conflict in &[Conflict::KeepBoth, Conflict::Overwrite] {
+            let ops = import_characters(&game.characters, &incoming, *conflict);
+            game.apply_as(Some(ALICE), Import(ops));
+        }
+        let points: Vec<u8> = game
+            .characters
+            .iter()
+            .map(|c| *c.value.influence_points.value())
+            .collect();
+        assert_eq!(points, vec![1]);
+        assert!(game.characters.iter().any(|c| c.id == id));
+
+        let (_, op) = game.characters.append(Character::default());
+        game.apply_as(Some(ALICE), CharacterTransition(op));
+        assert_eq!(game.characters.iter().count(), 2);
+    }
+
+    #[test]
+    fn only_an_actual_gm_kicks() {
+        let mut game = Game::default();
+        game.apply_as(Some(ALICE), Kick(BOB));
+        assert!(game.admits(BOB));
+        assert!(!game.locked);
+
+        game.apply(GmClaimed(GM));
+        game.apply_as(Some(ALICE), Kick(BOB));
+        assert!(game.admits(BOB));
+        game.apply_as(Some(GM), Kick(BOB));
+        assert!(!game.admits(BOB));
+        assert!(!game.admits(PlayerKey(4)));
+
+        game.apply_as(Some(GM), ReleaseGm);
+        assert!(game.admits(PlayerKey(4)));
+    }
+
+    fn influence(game: &Game, id: Uuid) -> u8 {
+        let character = game.characters.iter().find(|c| c.id == id).unwrap();
+        *character.value.influence_points.value()
+    }
+
+    #[test]
+    fn undo_cannot_take_back_influence_spent_on_a_reroll() {
+        let (mut game, id) = game_with_character(ALICE);
+        type_name(&mut game, ALICE, id, "Ada");
+
+        let expression: DiceExpression = "1d6".parse().unwrap();
+        let result = expression.roll(&mut rand::thread_rng());
+        game.apply_as(Some(ALICE), Roll(expression, Some(Roller::Character(id))));
+        game.apply_as(None, Resolve(result, Utc::now()));
+        game.apply_as(Some(ALICE), Reroll(vec![true], id));
+        assert_eq!(influence(&game, id), 0);
+
+        // Naming the character is no longer the last thing that happened
+        // to it, so the step is dropped instead
+        game.apply_as(Some(ALICE), Undo);
+        assert_eq!(influence(&game, id), 0);
+        assert_eq!(name(&game, id), "Ada");
+        game.apply_as(Some(ALICE), Redo);
+        assert_eq!(name(&game, id), "Ada");
+    }
+
+    #[test]
+    fn undo_cannot_take_back_somebody_elses_edits() {
+        let (mut game, id) = game_with_character(ALICE);
+        // Open to everybody
+        game.owners.clear();
+        type_name(&mut game, ALICE, id, "Ada");
+        type_name(&mut game, BOB, id, "Adam");
+
+        game.apply_as(Some(ALICE), Undo);
+        assert_eq!(name(&game, id), "Adam");
+        assert_eq!(game.characters.iter().count(), 1);
+        // Bob's own step is his to undo
+        game.apply_as(Some(BOB), Undo);
+        assert_eq!(name(&game, id), "Ada");
+    }
+
+    #[test]
+    fn redo_puts_back_what_undo_took_back() {
+        let (mut game, id) = game_with_character(ALICE);
+        type_name(&mut game, ALICE, id, "Ada");
+
+        game.apply_as(Some(ALICE), Undo);
+        assert_eq!(name(&game, id), "");
+        game.apply_as(Some(ALICE), Redo);
+        assert_eq!(name(&game, id), "Ada");
+        game.apply_as(Some(ALICE), Undo);
+        game.apply_as(Some(ALICE), Undo);
+        assert_eq!(game.characters.iter().count(), 0);
+        game.apply_as(Some(ALICE), Redo);
+        game.apply_as(Some(ALICE), Redo);
+        assert_eq!(name(&game, id), "Ada");
+        assert_eq!(game.owners.get(&id), Some(&ALICE));
+    }
 }
@@ -0,0 +1,24 @@
+use aper::data_structures::{List, ListItem, ListOperation, ZenoIndex};
+use aper::StateMachine;
+use uuid::Uuid;
+
+/// Moves the item `id` so that it ends up at position `to`, counting
+/// positions as they are before the move.
+pub fn move_item<T: StateMachine + PartialEq>(
+    list: &List<T>,
+    id: Uuid,
+    to: usize,
+) -> Option<ListOperation<T>> {
+    let others: Vec<ListItem<T>> = list.iter().filter(|item| item.id != id).collect();
+    let from = list.iter().position(|item| item.id == id)?;
+    // Removing the item shifts everything after it one step left
+    let to = if to > from { to - 1 } else { to }.min(others.len());
+
+    let location = match (to.checked_sub(1).map(|i| &others[i]), others.get(to)) {
+        (Some(before), Some(after)) => ZenoIndex::new_between(&before.location, &after.location)?,
+        (Some(before), None) => ZenoIndex::new_after(&before.location),
+        (None, Some(after)) => ZenoIndex::new_before(&after.location),
+        (None, None) => return None,
+    };
+    Some(ListOperation::Move(id, location))
+}
@@ -0,0 +1,189 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use uuid::Uuid;
+use x25519_dalek::StaticSecret;
+
+/// A player as the rest of the table sees them.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Player {
+    pub name: String,
+    pub key: PlayerKey,
+}
+
+/// Stands in for a player in the synced state: a hash of their public key.
+/// Connections only get one by proving they hold the matching secret key,
+/// see [`Identification`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PlayerKey(pub u64);
+
+impl PlayerKey {
+    pub fn of(public: &PublicKey) -> Self {
+        let hash = Sha256::digest(&public.0);
+        PlayerKey(u64::from_le_bytes(hash[..8].try_into().unwrap()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PublicKey(pub [u8; 32]);
+
+/// A secret only two parties can work out, each from their own secret key
+/// and the other's public key.
+pub type SharedKey = [u8; 32];
+
+/// Kept by whoever it belongs to, a browser or the server, and never sent
+/// anywhere.
+#[derive(Clone, PartialEq)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        SecretKey(ChaCha12Rng::from_entropy().gen())
+    }
+
+    pub fn public(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&StaticSecret::from(self.0)).to_bytes())
+    }
+
+    pub fn shared(&self, other: &PublicKey) -> SharedKey {
+        StaticSecret::from(self.0)
+            .diffie_hellman(&x25519_dalek::PublicKey::from(other.0))
+            .to_bytes()
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(SecretKey(bytes))
+    }
+}
+
+/// What players identify against, published by the server.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct ServerKey {
+    pub key: PublicKey,
+    /// Changes every time the server starts and every hour after, so that
+    /// an identification from before can't be sent again.
+    pub session: u64,
+}
+
+/// Shows that a connection's player holds the secret key for `key`.
+///
+/// Every transition is relayed to every client, so this must not give the
+/// secret away, and must be useless to anyone who copies it: `proof` can
+/// only be worked out from the player's secret key or the server's, and
+/// the server accepts each `nonce` once.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Identification {
+    pub key: PublicKey,
+    pub nonce: Uuid,
+    pub proof: [u8; 32],
+}
+
+impl Identification {
+    pub fn new(secret: &SecretKey, server: &ServerKey, nonce: Uuid) -> Self {
+        Self {
+            key: secret.public(),
+            nonce,
+            proof: proof(&secret.shared(&server.key), server.session, &nonce),
+        }
+    }
+
+    /// Only the server can check this. It is up to the server to only
+    /// accept a nonce once.
+    pub fn verify(&self, server_secret: &SecretKey, session: u64) -> bool {
+        let shared = server_secret.shared(&self.key);
+        // What a key of low order gives, whoever works it out
+        if shared == [0; 32] {
+            return false;
+        }
+        let expected = proof(&shared, session, &self.nonce);
+        // Compared in constant time
+        expected
+            .iter()
+            .zip(&self.proof)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+fn proof(shared: &SharedKey, session: u64, nonce: &Uuid) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(b"roll4 identify");
+    hash.update(shared);
+    hash.update(session.to_le_bytes());
+    hash.update(nonce.as_bytes());
+    hash.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> (SecretKey, ServerKey) {
+        let secret = SecretKey::generate();
+        let key = ServerKey {
+            key: secret.public(),
+            session: 7,
+        };
+        (secret, key)
+    }
+
+    #[test]
+    fn identification_is_checked_by_the_server() {
+        let (server_secret, server) = server();
+        let player = SecretKey::generate();
+        let identification = Identification::new(&player, &server, Uuid::from_u128(1));
+        assert!(identification.verify(&server_secret, server.session));
+        assert_eq!(
+            PlayerKey::of(&identification.key),
+            PlayerKey::of(&player.public())
+        );
+    }
+
+    #[test]
+    fn identification_only_holds_for_its_key_session_and_server() {
+        let (server_secret, server) = server();
+        let player = SecretKey::generate();
+        let identification = Identification::new(&player, &server, Uuid::from_u128(1));
+
+        let other_player = Identification {
+            key: SecretKey::generate().public(),
+            ..identification.clone()
+        };
+        assert!(!other_player.verify(&server_secret, server.session));
+        let other_nonce = Identification {
+            nonce: Uuid::from_u128(2),
+            ..identification.clone()
+        };
+        assert!(!other_nonce.verify(&server_secret, server.session));
+        assert!(!identification.verify(&server_secret, server.session + 1));
+        assert!(!identification.verify(&SecretKey::generate(), server.session));
+
+        let low_order = Identification {
+            key: PublicKey([0; 32]),
+            nonce: Uuid::from_u128(3),
+            proof: proof(&[0; 32], server.session, &Uuid::from_u128(3)),
+        };
+        assert!(!low_order.verify(&server_secret, server.session));
+    }
+
+    #[test]
+    fn secret_keys_survive_hex() {
+        let secret = SecretKey::generate();
+        assert!(SecretKey::from_hex(&secret.to_hex()) == Some(secret));
+        assert!(SecretKey::from_hex("00").is_none());
+        assert!(SecretKey::from_hex(&"g".repeat(64)).is_none());
+    }
+}
@@ -0,0 +1,499 @@
+use crate::dice::Roller;
+use crate::journal::JournalEntry;
+use crate::player::{Identification, Player, PlayerKey, PublicKey, SecretKey, ServerKey};
+use crate::sealed::Sealed;
+use crate::{Game, GameTransition};
+use aper::{PlayerID, StateMachine, StateProgram, Transition, TransitionEvent};
+use chrono::{DateTime, Duration, Utc};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// The state that aper synchronizes between the server and the clients.
+///
+/// Every room is an independent [`Game`]. Rooms are opened when somebody
+/// joins them and closed again once nothing has happened in them for a
+/// while; the server keeps closed rooms in a [`RoomStore`].
+///
+/// A connection may only change the room it joined, but every client is
+/// sent the whole program, so anyone can read every open room. Rooms keep
+/// groups apart; they don't keep secrets from each other.
+///
+/// Rolling dice is split in two: a player asks for a roll, and the server
+/// answers with a [`GameTransition::Resolve`] carrying the results. The
+/// random number generator never leaves the server, so clients cannot work
+/// out upcoming rolls.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GameProgram {
+    pub rooms: BTreeMap<String, Room>,
+    /// Rooms somebody has joined that the server has not opened yet.
+    pub joining: BTreeSet<String>,
+    /// Rooms the server could not read from its store. They stay closed
+    /// until somebody joins them again, so the store is left as it was.
+    #[serde(default)]
+    pub unavailable: BTreeSet<String>,
+    /// The room each connection joined last, the only one it may change.
+    /// Forgotten when the room closes or the connection is gone.
+    #[serde(default)]
+    pub joined: Vec<(PlayerID, String)>,
+    /// Connections whose player has identified themselves.
+    pub players: Vec<(PlayerID, Player)>,
+    /// What players identify against. Set by the server.
+    pub server_key: Option<ServerKey>,
+    /// Moved forward by the server every [`HEARTBEAT_SECONDS`], so that
+    /// clients notice when they stop hearing from it.
+    pub heartbeat: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    server: Option<Server>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Room {
+    pub game: Game,
+    pub last_active: DateTime<Utc>,
+}
+
+#[derive(Transition, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ProgramTransition {
+    Join(String),
+    /// A player's name and proof of who they are. Only the server can check
+    /// it, and answers with [`ProgramTransition::Identified`].
+    Identify(String, Identification),
+    /// A connection's player proved who they are. Only accepted from the
+    /// server.
+    Identified(PlayerID, Player),
+    InRoom(String, GameTransition),
+    /// Brings a room into memory. Only accepted from the server.
+    Open(String, Game),
+    /// Takes an idle room out of memory. Only accepted from the server.
+    Close(String),
+    /// The room could not be read. Only accepted from the server.
+    Unavailable(String),
+    /// Only accepted from the server.
+    Heartbeat(DateTime<Utc>),
+    /// Sent by clients every [`PRESENT_SECONDS`], so that the server can
+    /// tell which connections are gone.
+    Present,
+    /// Nothing was heard from a connection for a while, so it is
+    /// forgotten. Only accepted from the server.
+    Gone(PlayerID),
+    /// Replaces the session players identify against, so that the
+    /// identifications seen in the last one can be forgotten. Only accepted
+    /// from the server.
+    NewSession(ServerKey),
+}
+use ProgramTransition::*;
+
+pub const HEARTBEAT_SECONDS: i64 = 10;
+pub const PRESENT_SECONDS: i64 = 60;
+/// How long a connection may stay silent before it is gone.
+const GONE_SECONDS: i64 = 3 * PRESENT_SECONDS;
+const SESSION_MINUTES: i64 = 60;
+
+/// Where the server keeps rooms while they are not open.
+pub trait RoomStore: Send + Sync {
+    /// `Ok(None)` for a room that was never saved. An error keeps the room
+    /// from being opened, so that it isn't started over and saved on top
+    /// of what could not be read.
+    fn load(&self, room: &str) -> io::Result<Option<Game>>;
+    /// Called when a room is opened, with the game it starts from.
+    fn opened(&self, room: &str, game: &Game);
+    /// Called after every transition applied to an open room, with the
+    /// transition that was applied.
+    fn changed(&self, room: &str, game: &Game, entry: &JournalEntry);
+    /// Called when a room is closed.
+    fn closed(&self, room: &str, game: &Game);
+    /// Checks `password` against the room's GM password, which never
+    /// leaves the store. A room without one takes `password` as its own if
+    /// `may_set`.
+    fn claim_gm(&self, room: &str, password: &str, may_set: bool) -> bool;
+}
+
+/// The parts of [`GameProgram`] that only exist on the server.
+#[derive(Clone)]
+struct Server {
+    rng: Arc<Mutex<ChaCha12Rng>>,
+    store: Arc<dyn RoomStore>,
+    idle_timeout: Duration,
+    secret: SecretKey,
+    /// When the current [`ServerKey::session`] started.
+    session_start: DateTime<Utc>,
+    /// Identifications already seen this session, which may not be used
+    /// again.
+    nonces: Arc<Mutex<HashSet<Uuid>>>,
+    /// Identifications that checked out, waiting to be announced.
+    identified: Arc<Mutex<Vec<(PlayerID, Player)>>>,
+    /// Public keys of the players who are connected, needed to seal and
+    /// reveal hidden rolls.
+    keys: Arc<Mutex<HashMap<PlayerKey, PublicKey>>>,
+    /// When each connection was last heard from.
+    seen: Arc<Mutex<HashMap<PlayerID, DateTime<Utc>>>>,
+    /// GM claims with the right password, waiting to be announced.
+    claims: Arc<Mutex<Vec<(String, PlayerKey)>>>,
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Server")
+    }
+}
+
+/// Room names end up in URLs and file names, so they are kept simple.
+pub fn valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl GameProgram {
+    /// Creates the server's copy of the program, the only one that can roll
+    /// dice, open or close rooms and check who players are. `secret` is the
+    /// server's own key, which has to stay the same across restarts for
+    /// hidden rolls to be revealed.
+    pub fn server(store: Arc<dyn RoomStore>, secret: SecretKey) -> Self {
+        let mut rng = ChaCha12Rng::from_entropy();
+        Self {
+            rooms: BTreeMap::new(),
+            joining: BTreeSet::new(),
+            unavailable: BTreeSet::new(),
+            joined: Vec::new(),
+            players: Vec::new(),
+            server_key: Some(ServerKey {
+                key: secret.public(),
+                session: rng.gen(),
+            }),
+            heartbeat: None,
+            server: Some(Server {
+                rng: Arc::new(Mutex::new(rng)),
+                store,
+                idle_timeout: Duration::minutes(30),
+                secret,
+                session_start: Utc::now(),
+                nonces: Arc::new(Mutex::new(HashSet::new())),
+                identified: Arc::new(Mutex::new(Vec::new())),
+                keys: Arc::new(Mutex::new(HashMap::new())),
+                seen: Arc::new(Mutex::new(HashMap::new())),
+                claims: Arc::new(Mutex::new(Vec::new())),
+            }),
+        }
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        if let Some(server) = &mut self.server {
+            server.idle_timeout = timeout;
+        }
+        self
+    }
+
+    pub fn room(&self, name: &str) -> Option<&Game> {
+        self.rooms.get(name).map(|r| &r.game)
+    }
+
+    pub fn player(&self, id: PlayerID) -> Option<&Player> {
+        self.players.iter().find(|(p, _)| *p == id).map(|(_, p)| p)
+    }
+
+    pub fn player_by_key(&self, key: PlayerKey) -> Option<&Player> {
+        self.players.iter().map(|(_, p)| p).find(|p| p.key == key)
+    }
+
+    fn has_joined(&self, id: Option<PlayerID>, room: &str) -> bool {
+        self.joined.iter().any(|(p, r)| Some(*p) == id && r == room)
+    }
+}
+
+impl StateMachine for GameProgram {
+    type Transition = TransitionEvent<ProgramTransition>;
+
+    fn apply(&mut self, event: Self::Transition) {
+        let from_server = event.player.is_none();
+        let actor = event.player.and_then(|p| self.player(p)).map(|p| p.key);
+        if let (Some(id), Some(server)) = (event.player, &self.server) {
+            server.seen.lock().unwrap().insert(id, event.timestamp);
+        }
+        match event.transition {
+            Join(name) => {
+                if let Some(id) = event.player {
+                    self.joined.retain(|(p, _)| *p != id);
+                    self.joined.push((id, name.clone()));
+                }
+                if let Some(room) = self.rooms.get_mut(&name) {
+                    room.last_active = event.timestamp;
+                } else if valid_room_name(&name) {
+                    // Joining an unavailable room tries reading it again
+                    self.unavailable.remove(&name);
+                    self.joining.insert(name);
+                }
+            }
+            Identify(name, identification) => {
+                let (id, server, server_key) = match (event.player, &self.server, self.server_key) {
+                    (Some(id), Some(server), Some(server_key)) => (id, server, server_key),
+                    _ => return,
+                };
+                let fresh = server.nonces.lock().unwrap().insert(identification.nonce);
+                if fresh && identification.verify(&server.secret, server_key.session) {
+                    let key = PlayerKey::of(&identification.key);
+                    server.keys.lock().unwrap().insert(key, identification.key);
+                    server
+                        .identified
+                        .lock()
+                        .unwrap()
+                        .push((id, Player { name, key }));
+                }
+            }
+            Identified(id, player) => {
+                if !from_server {
+                    return;
+                }
+                if let Some(server) = &self.server {
+                    server
+                        .identified
+                        .lock()
+                        .unwrap()
+                        .retain(|(p, q)| !(*p == id && *q == player));
+                }
+                self.players.retain(|(p, _)| *p != id);
+                self.players.push((id, player));
+            }
+            InRoom(name, transition) => {
+                let allowed = from_server
+                    || (!transition.server_only() && self.has_joined(event.player, &name));
+                if !allowed {
+                    return;
+                }
+                if let (Some(server), GameTransition::GmClaimed(player)) =
+                    (&self.server, &transition)
+                {
+                    let claim = (name.clone(), *player);
+                    server.claims.lock().unwrap().retain(|c| *c != claim);
+                }
+                let room = match self.rooms.get(&name) {
+                    Some(room) => &room.game,
+                    None => return,
+                };
+                // Kicks and the GM are tied to player keys, which an
+                // unidentified connection would get around
+                if !from_server && actor.is_none() && (room.gm.is_some() || room.locked) {
+                    return;
+                }
+                if let (Some(server), GameTransition::ClaimGm(sealed), Some(actor)) =
+                    (&self.server, &transition, actor)
+                {
+                    if room.admits(actor) {
+                        let password = server
+                            .keys
+                            .lock()
+                            .unwrap()
+                            .get(&actor)
+                            .and_then(|key| sealed.open(&server.secret.shared(key)));
+                        // A GM who has no password yet is the only one who may set it
+                        let may_set = room.gm.map_or(true, |gm| gm == actor);
+                        if let Some(password) = password {
+                            if server.store.claim_gm(&name, &password, may_set) {
+                                server.claims.lock().unwrap().push((name.clone(), actor));
+                            }
+                        }
+                    }
+                }
+                let author_name = actor
+                    .and_then(|key| self.player_by_key(key))
+                    .map(|p| p.name.clone());
+                // Players roll under the name they identified with
+                let transition = match transition {
+                    GameTransition::Roll(x, Some(Roller::Player(_))) => {
+                        GameTransition::Roll(x, author_name.clone().map(Roller::Player))
+                    }
+                    transition => transition,
+                };
+                if let Some(room) = self.rooms.get_mut(&name) {
+                    let time = event.timestamp;
+                    let entry = self.server.as_ref().map(|_| JournalEntry {
+                        time,
+                        author: actor,
+                        name: author_name,
+                        transition: transition.clone(),
+                    });
+                    room.game.apply_as(actor, transition);
+                    room.last_active = event.timestamp;
+
+                    if let (Some(server), Some(entry)) = (&self.server, entry) {
+                        server.store.changed(&name, &room.game, &entry);
+                    }
+                }
+            }
+            Open(name, game) => {
+                if from_server && self.joining.remove(&name) {
+                    if let Some(server) = &self.server {
+                        server.store.opened(&name, &game);
+                    }
+                    self.rooms.insert(
+                        name,
+                        Room {
+                            game,
+                            last_active: event.timestamp,
+                        },
+                    );
+                }
+            }
+            Close(name) => {
+                if !from_server {
+                    return;
+                }
+                if let Some(room) = self.rooms.remove(&name) {
+                    if let Some(server) = &self.server {
+                        server.store.closed(&name, &room.game);
+                    }
+                    // Clients join again to open it
+                    self.joined.retain(|(_, r)| *r != name);
+                }
+            }
+            Unavailable(name) => {
+                if from_server && self.joining.remove(&name) {
+                    self.unavailable.insert(name);
+                }
+            }
+            Heartbeat(time) => {
+                if from_server {
+                    self.heartbeat = Some(time);
+                }
+            }
+            Present => {}
+            Gone(id) => {
+                if !from_server {
+                    return;
+                }
+                self.joined.retain(|(p, _)| *p != id);
+                self.players.retain(|(p, _)| *p != id);
+                if let Some(server) = &self.server {
+                    server.seen.lock().unwrap().remove(&id);
+                    // Keys of players who are still connected, or about to
+                    // be announced
+                    let identified = server.identified.lock().unwrap();
+                    let players = self.players.iter().chain(identified.iter());
+                    let connected: HashSet<PlayerKey> = players.map(|(_, p)| p.key).collect();
+                    server
+                        .keys
+                        .lock()
+                        .unwrap()
+                        .retain(|key, _| connected.contains(key));
+                }
+            }
+            NewSession(key) => {
+                if !from_server {
+                    return;
+                }
+                self.server_key = Some(key);
+                if let Some(server) = &mut self.server {
+                    server.nonces.lock().unwrap().clear();
+                    server.session_start = event.timestamp;
+                }
+            }
+        }
+    }
+}
+
+impl StateProgram<ProgramTransition> for GameProgram {
+    fn suspended_event(&self) -> Option<TransitionEvent<ProgramTransition>> {
+        let server = self.server.as_ref()?;
+        let now = Utc::now();
+        let from_server = |timestamp, transition| {
+            Some(TransitionEvent {
+                timestamp,
+                player: None,
+                transition,
+            })
+        };
+
+        if let Some((id, player)) = server.identified.lock().unwrap().first() {
+            return from_server(now, Identified(*id, player.clone()));
+        }
+        if let Some((name, player)) = server.claims.lock().unwrap().first() {
+            return from_server(
+                now,
+                InRoom(name.clone(), GameTransition::GmClaimed(*player)),
+            );
+        }
+
+        let keys = server.keys.lock().unwrap();
+        let shared = |player: &PlayerKey| keys.get(player).map(|k| server.secret.shared(k));
+        let mut rng = server.rng.lock().unwrap();
+        for (name, room) in &self.rooms {
+            let game = &room.game;
+            let dice = &game.dice;
+            if let Some(pending) = &dice.pending {
+                if let Some(result) = pending.request.resolve(dice.result.as_ref(), &mut *rng) {
+                    let transition = if !pending.hidden {
+                        GameTransition::Resolve(result, now)
+                    } else {
+                        // Called off if the GM is gone or hasn't identified
+                        // since a restart, as it would block every other roll
+                        let sealed = game.gm.and_then(|gm| {
+                            Some(Sealed::seal(&result, gm, &shared(&gm)?, rng.gen()))
+                        });
+                        GameTransition::ResolveHidden(sealed, now)
+                    };
+                    return from_server(now, InRoom(name.clone(), transition));
+                }
+            }
+
+            for (i, record) in game.log.iter().enumerate() {
+                if let (true, Some(sealed)) = (record.reveal_requested, &record.sealed) {
+                    if let Some(result) =
+                        shared(&sealed.for_player).and_then(|key| sealed.open(&key))
+                    {
+                        return from_server(
+                            now,
+                            InRoom(name.clone(), GameTransition::Revealed(i, result, now)),
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Some(name) = self.joining.iter().next() {
+            return match server.store.load(name) {
+                Ok(game) => {
+                    let mut game = game.unwrap_or_default();
+                    game.forget_legacy_keys();
+                    from_server(now, Open(name.clone(), game))
+                }
+                Err(_) => from_server(now, Unavailable(name.clone())),
+            };
+        }
+
+        let beat = self
+            .heartbeat
+            .map_or(now, |t| t + Duration::seconds(HEARTBEAT_SECONDS));
+        let gone = server
+            .seen
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, seen)| (*seen + Duration::seconds(GONE_SECONDS), *id))
+            .min_by_key(|(time, _)| *time);
+        if let Some((time, id)) = gone.filter(|(time, _)| *time < beat) {
+            return from_server(time, Gone(id));
+        }
+        let session_end = server.session_start + Duration::minutes(SESSION_MINUTES);
+        if session_end < beat {
+            let key = ServerKey {
+                key: server.secret.public(),
+                session: rng.gen(),
+            };
+            return from_server(session_end, NewSession(key));
+        }
+        match self.rooms.iter().min_by_key(|(_, r)| r.last_active) {
+            Some((name, room)) if room.last_active + server.idle_timeout < beat => {
+                from_server(room.last_active + server.idle_timeout, Close(name.clone()))
+            }
+            _ => from_server(beat, Heartbeat(beat)),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use crate::Game;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// Each migration upgrades a save by one version: `MIGRATIONS[n]` turns a
+/// version `n` save into a version `n + 1` save.
+const MIGRATIONS: &[fn(Value) -> Value] = &[from_bare_list, to_full_game];
+
+/// The version written by [`SaveFile::to_json`].
+pub const SAVE_VERSION: u64 = MIGRATIONS.len() as u64;
+
+/// What the client's save button writes and loads.
+///
+/// Older saves are upgraded step by step when they are read, so a change to
+/// the format only needs a new entry at the end of `MIGRATIONS`. Changes
+/// inside [`Game`] are handled by its own serde defaults and migrations.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SaveFile {
+    pub version: u64,
+    /// The room the save was made in.
+    pub room: Option<String>,
+    pub saved: Option<DateTime<Utc>>,
+    pub game: Game,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SaveError {
+    Corrupt(String),
+    NotASave,
+    TooNew(u64),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SaveError::*;
+        match self {
+            Corrupt(e) => write!(f, "the file is damaged: {}", e),
+            NotASave => write!(f, "the file is not a save"),
+            TooNew(v) => write!(
+                f,
+                "the save is version {} but only up to version {} can be read",
+                v, SAVE_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl SaveFile {
+    /// Leaves out the GM, so that whoever loads the save keeps their own,
+    /// any roll still waiting for the server, and the undo history.
+    pub fn new(room: Option<String>, saved: DateTime<Utc>, game: &Game) -> Self {
+        let mut game = game.clone();
+        game.gm = None;
+        game.dice.pending = None;
+        game.undo.clear();
+        Self {
+            version: SAVE_VERSION,
+            room,
+            saved: Some(saved),
+            game,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Reads a save of any version up to [`SAVE_VERSION`].
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, SaveError> {
+        let mut value: Value =
+            serde_json::from_slice(bytes).map_err(|e| SaveError::Corrupt(e.to_string()))?;
+
+        // Saves from before there was a version are a bare character list
+        let version = match value.get("version") {
+            None => 0,
+            Some(v) => v.as_u64().ok_or(SaveError::NotASave)?,
+        };
+        if version > SAVE_VERSION {
+            return Err(SaveError::TooNew(version));
+        }
+        for migrate in &MIGRATIONS[version as usize..] {
+            value = migrate(value);
+        }
+
+        serde_json::from_value(value).map_err(|e| {
+            if e.is_data() {
+                SaveError::NotASave
+            } else {
+                SaveError::Corrupt(e.to_string())
+            }
+        })
+    }
+}
+
+/// Version 0 was the character list on its own.
+fn from_bare_list(characters: Value) -> Value {
+    json!({
+        "version": 1,
+        "room": null,
+        "saved": null,
+        "characters": characters,
+    })
+}
+
+/// Version 1 only had the characters.
+fn to_full_game(mut save: Value) -> Value {
+    let mut game = serde_json::to_value(Game::default()).unwrap();
+    if let Some(characters) = save.get_mut("characters") {
+        game["characters"] = characters.take();
+    }
+    json!({
+        "version": 2,
+        "room": save["room"].take(),
+        "saved": save["saved"].take(),
+        "game": game,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::Text;
+    use crate::Character;
+    use aper::data_structures::List;
+    use aper::StateMachine;
+
+    fn characters() -> List<Character> {
+        let mut characters = List::new();
+        let (_, op) = characters.append(Character {
+            name: Text::new("Ada"),
+            ..Character::default()
+        });
+        characters.apply(op);
+        characters
+    }
+
+    fn names(save: &SaveFile) -> Vec<String> {
+        let characters = save.game.characters.iter();
+        characters.map(|c| c.value.name.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_a_bare_character_list() {
+        let bytes = serde_json::to_vec(&characters()).unwrap();
+        let save = SaveFile::from_slice(&bytes).unwrap();
+        assert_eq!(save.version, SAVE_VERSION);
+        assert_eq!(save.room, None);
+        assert_eq!(names(&save), vec!["Ada".to_string()]);
+    }
+
+    #[test]
+    fn upgrades_a_version_1_save() {
+        let saved = Utc::now();
+        let v1 = json!({
+            "version": 1,
+            "room": "den",
+            "saved": saved,
+            "characters": characters(),
+        });
+        let save = SaveFile::from_slice(v1.to_string().as_bytes()).unwrap();
+        assert_eq!(save.version, SAVE_VERSION);
+        assert_eq!(save.room.as_deref(), Some("den"));
+        assert_eq!(save.saved, Some(saved));
+        assert_eq!(names(&save), vec!["Ada".to_string()]);
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let game = Game {
+            characters: characters(),
+            ..Game::default()
+        };
+        let save = SaveFile::new(Some("den".to_string()), Utc::now(), &game);
+        assert_eq!(SaveFile::from_slice(save.to_json().as_bytes()), Ok(save));
+    }
+
+    #[test]
+    fn reports_what_it_cannot_read() {
+        let save = SaveFile::new(None, Utc::now(), &Game::default());
+        let mut newer = serde_json::to_value(&save).unwrap();
+        newer["version"] = json!(SAVE_VERSION + 1);
+        assert_eq!(
+            SaveFile::from_slice(newer.to_string().as_bytes()),
+            Err(SaveError::TooNew(SAVE_VERSION + 1))
+        );
+
+        let odd = json!({"version": "two", "game": {}});
+        assert_eq!(
+            SaveFile::from_slice(odd.to_string().as_bytes()),
+            Err(SaveError::NotASave)
+        );
+
+        let json = save.to_json();
+        let truncated = &json.as_bytes()[..json.len() / 2];
+        assert!(matches!(
+            SaveFile::from_slice(truncated),
+            Err(SaveError::Corrupt(_))
+        ));
+    }
+}
@@ -0,0 +1,76 @@
+use crate::expression::RollResult;
+use crate::player::{PlayerKey, SharedKey};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A roll result that only one player can read.
+///
+/// Every client receives the whole game, so a hidden roll cannot simply be
+/// left out of it. Instead the server encrypts it with the key it shares
+/// with the player, see [`crate::player::SecretKey::shared`]. Only the
+/// server and that player can work the key out.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Sealed {
+    pub for_player: PlayerKey,
+    pub nonce: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl Sealed {
+    pub fn seal(result: &RollResult, for_player: PlayerKey, key: &SharedKey, nonce: u64) -> Self {
+        let mut bytes = serde_json::to_vec(result).unwrap();
+        apply_keystream(&mut bytes, b"roll4 sealed", key, nonce);
+        Self {
+            for_player,
+            nonce,
+            bytes,
+        }
+    }
+
+    /// `None` if `key` isn't the one the result was sealed with.
+    pub fn open(&self, key: &SharedKey) -> Option<RollResult> {
+        let mut bytes = self.bytes.clone();
+        apply_keystream(&mut bytes, b"roll4 sealed", key, self.nonce);
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+/// A GM password on its way to the server, which only the server can read.
+/// It is encrypted like [`Sealed`], with the key the claiming player shares
+/// with the server.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SealedPassword {
+    pub nonce: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl SealedPassword {
+    pub fn seal(password: &str, key: &SharedKey, nonce: u64) -> Self {
+        let mut bytes = password.as_bytes().to_vec();
+        apply_keystream(&mut bytes, b"roll4 password", key, nonce);
+        Self { nonce, bytes }
+    }
+
+    pub fn open(&self, key: &SharedKey) -> Option<String> {
+        let mut bytes = self.bytes.clone();
+        apply_keystream(&mut bytes, b"roll4 password", key, self.nonce);
+        String::from_utf8(bytes).ok()
+    }
+}
+
+/// `purpose` keeps keystreams for different kinds of secrets apart, as they
+/// share keys and nonces are picked at random.
+fn apply_keystream(bytes: &mut [u8], purpose: &[u8], key: &SharedKey, nonce: u64) {
+    let mut seed = Sha256::new();
+    seed.update(purpose);
+    seed.update(key);
+    seed.update(nonce.to_le_bytes());
+
+    let mut keystream = vec![0; bytes.len()];
+    ChaCha12Rng::from_seed(seed.finalize().into()).fill_bytes(&mut keystream);
+    for (b, k) in bytes.iter_mut().zip(keystream) {
+        *b ^= k;
+    }
+}
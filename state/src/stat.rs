@@ -0,0 +1,73 @@
+use aper::data_structures::Atom;
+use aper::{StateMachine, Transition};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+
+/// Like an [`Atom`], but only holds values from `MIN` to `MAX`. Anything
+/// outside the range is clamped, whether it arrives in a transition or in
+/// a loaded save.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Atom<T>", into = "Atom<T>")]
+// `StatValue` already asks for what serde would add as bounds
+#[serde(bound = "")]
+pub struct Stat<T: StatValue, const MIN: i16, const MAX: i16> {
+    value: T,
+}
+
+pub trait StatValue:
+    Copy + Into<i16> + TryFrom<i16> + Serialize + DeserializeOwned + Debug + PartialEq + 'static
+{
+}
+impl StatValue for u8 {}
+impl StatValue for i8 {}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetStat<T>(T);
+
+impl<T: StatValue> Transition for SetStat<T> {}
+
+impl<T: StatValue, const MIN: i16, const MAX: i16> Stat<T, MIN, MAX> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value: Self::clamp(value),
+        }
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn replace(&self, value: T) -> SetStat<T> {
+        SetStat(value)
+    }
+
+    fn clamp(value: T) -> T {
+        let value: i16 = value.into();
+        match T::try_from(value.max(MIN).min(MAX)) {
+            Ok(v) => v,
+            Err(_) => unreachable!("stat range does not fit its type"),
+        }
+    }
+}
+
+impl<T: StatValue, const MIN: i16, const MAX: i16> StateMachine for Stat<T, MIN, MAX> {
+    type Transition = SetStat<T>;
+
+    fn apply(&mut self, SetStat(value): Self::Transition) {
+        self.value = Self::clamp(value);
+    }
+}
+
+impl<T: StatValue, const MIN: i16, const MAX: i16> From<Atom<T>> for Stat<T, MIN, MAX> {
+    fn from(atom: Atom<T>) -> Self {
+        Self::new(*atom.value())
+    }
+}
+
+impl<T: StatValue, const MIN: i16, const MAX: i16> From<Stat<T, MIN, MAX>> for Atom<T> {
+    fn from(stat: Stat<T, MIN, MAX>) -> Self {
+        Atom::new(stat.value)
+    }
+}
@@ -0,0 +1,315 @@
+use aper::data_structures::Atom;
+use aper::{StateMachine, Transition};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+/// Identifies one character of a [`Text`]: the run of text it was typed in
+/// and its place in that run.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CharId {
+    pub run: Uuid,
+    pub offset: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Char {
+    id: CharId,
+    c: char,
+    /// Deleted characters are kept so that edits made at the same time can
+    /// still find their place.
+    deleted: bool,
+}
+
+/// A string that merges edits made by several people at once.
+///
+/// Edits refer to the characters around them rather than to positions, so
+/// two players typing in different parts of the same field both keep what
+/// they typed, and text typed at the same spot stays together.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "TextData", into = "Vec<Span>")]
+pub struct Text {
+    chars: Vec<Char>,
+}
+
+/// Characters are saved in runs, as they are usually typed together.
+#[derive(Clone, Serialize, Deserialize)]
+struct Span {
+    run: Uuid,
+    start: u32,
+    text: String,
+    #[serde(default)]
+    deleted: bool,
+}
+
+/// What a [`Text`] may look like in a save. Before it existed these fields
+/// were atoms holding a string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TextData {
+    Spans(Vec<Span>),
+    Plain(String),
+    Atom(Atom<String>),
+}
+
+#[derive(Transition, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct TextTransition {
+    /// The first character of each stretch to delete, and its length.
+    delete: Vec<(CharId, u32)>,
+    /// New text, and the character it goes after, `None` for the start.
+    insert: Option<(Option<CharId>, Uuid, String)>,
+}
+
+impl Text {
+    pub fn new(text: &str) -> Self {
+        // Only one run, so its ID only needs to differ from later ones
+        Self::inserted(Uuid::nil(), text, 0)
+    }
+
+    fn inserted(run: Uuid, text: &str, start: u32) -> Self {
+        let chars = text
+            .chars()
+            .zip(start..)
+            .map(|(c, offset)| Char {
+                id: CharId { run, offset },
+                c,
+                deleted: false,
+            })
+            .collect();
+        Self { chars }
+    }
+
+    fn visible(&self) -> impl Iterator<Item = &Char> {
+        self.chars.iter().filter(|c| !c.deleted)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.visible().next().is_none()
+    }
+
+    /// The last of the first `index` visible characters, `None` for the
+    /// start. Unlike `index`, it keeps its place as others edit the text.
+    pub fn anchor(&self, index: usize) -> Option<CharId> {
+        self.visible().take(index).last().map(|c| c.id)
+    }
+
+    /// How many visible characters there are up to and including `anchor`.
+    pub fn position(&self, anchor: Option<CharId>) -> usize {
+        let anchor = match anchor {
+            Some(anchor) => anchor,
+            None => return 0,
+        };
+        match self.chars.iter().position(|c| c.id == anchor) {
+            Some(i) => self.chars[..=i].iter().filter(|c| !c.deleted).count(),
+            None => self.visible().count(),
+        }
+    }
+
+    /// The transition that turns this text into `new`. `run` must be new,
+    /// as it names the characters being added.
+    pub fn edit(&self, new: &str, run: Uuid) -> TextTransition {
+        let old: Vec<&Char> = self.visible().collect();
+        let new: Vec<char> = new.chars().collect();
+
+        let prefix = old
+            .iter()
+            .zip(&new)
+            .take_while(|(a, b)| a.c == **b)
+            .count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a.c == **b)
+            .count();
+
+        let mut delete: Vec<(CharId, u32)> = Vec::new();
+        for c in &old[prefix..old.len() - suffix] {
+            match delete.last_mut() {
+                Some((first, count))
+                    if first.run == c.id.run && first.offset + *count == c.id.offset =>
+                {
+                    *count += 1
+                }
+                _ => delete.push((c.id, 1)),
+            }
+        }
+
+        let inserted: String = new[prefix..new.len() - suffix].iter().collect();
+        let insert = if inserted.is_empty() {
+            None
+        } else {
+            let after = prefix.checked_sub(1).map(|i| old[i].id);
+            Some((after, run, inserted))
+        };
+
+        TextTransition { delete, insert }
+    }
+}
+
+impl StateMachine for Text {
+    type Transition = TextTransition;
+
+    fn apply(&mut self, transition: Self::Transition) {
+        for (first, count) in transition.delete {
+            // `count` comes from a client, so it is never looped over
+            let offsets = first.offset..first.offset.saturating_add(count);
+            for c in &mut self.chars {
+                if c.id.run == first.run && offsets.contains(&c.id.offset) {
+                    c.deleted = true;
+                }
+            }
+        }
+
+        if let Some((after, run, text)) = transition.insert {
+            // Runs are never reused, so this one was inserted already
+            if self.chars.iter().any(|c| c.id.run == run) {
+                return;
+            }
+            // Characters typed at the same spot earlier end up after these
+            let at = match after {
+                None => 0,
+                Some(after) => match self.chars.iter().position(|c| c.id == after) {
+                    Some(i) => i + 1,
+                    None => self.chars.len(),
+                },
+            };
+            let new = Self::inserted(run, &text, 0).chars;
+            self.chars.splice(at..at, new);
+        }
+    }
+}
+
+impl fmt::Display for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.visible().try_for_each(|c| write!(f, "{}", c.c))
+    }
+}
+
+impl From<TextData> for Text {
+    fn from(data: TextData) -> Self {
+        let spans = match data {
+            TextData::Spans(spans) => spans,
+            TextData::Plain(text) => return Self::new(&text),
+            TextData::Atom(atom) => return Self::new(atom.value()),
+        };
+        let chars = spans
+            .into_iter()
+            .flat_map(|span| {
+                let mut text = Self::inserted(span.run, &span.text, span.start).chars;
+                text.iter_mut().for_each(|c| c.deleted = span.deleted);
+                text
+            })
+            .collect();
+        Self { chars }
+    }
+}
+
+impl From<Text> for Vec<Span> {
+    fn from(text: Text) -> Self {
+        let mut spans: Vec<Span> = Vec::new();
+        for c in text.chars {
+            match spans.last_mut() {
+                Some(span)
+                    if span.run == c.id.run
+                        && span.deleted == c.deleted
+                        && span.start + span.text.chars().count() as u32 == c.id.offset =>
+                {
+                    span.text.push(c.c)
+                }
+                _ => spans.push(Span {
+                    run: c.id.run,
+                    start: c.id.offset,
+                    text: c.c.to_string(),
+                    deleted: c.deleted,
+                }),
+            }
+        }
+        spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(text: &Text, transitions: &[&TextTransition]) -> Text {
+        let mut text = text.clone();
+        for t in transitions {
+            text.apply((*t).clone());
+        }
+        text
+    }
+
+    #[test]
+    fn inserts_at_the_same_spot_end_up_the_same_everywhere() {
+        let base = Text::new("ab");
+        let alice = base.edit("aXXb", Uuid::from_u128(1));
+        let bob = base.edit("aYYb", Uuid::from_u128(2));
+
+        // Every copy applies them in the order the server relays them
+        let server = applied(&base, &[&alice, &bob]);
+        let alices_copy = applied(&base, &[&alice, &bob]);
+        // A client that joined in between was sent the text as it was
+        let sent = serde_json::to_string(&applied(&base, &[&alice])).unwrap();
+        let late_copy = applied(&serde_json::from_str(&sent).unwrap(), &[&bob]);
+        assert_eq!(server.to_string(), "aYYXXb");
+        assert_eq!(alices_copy, server);
+        assert_eq!(late_copy, server);
+    }
+
+    #[test]
+    fn text_inserted_into_a_deleted_range_survives() {
+        let base = Text::new("abcd");
+        let delete = base.edit("ad", Uuid::from_u128(1));
+        let insert = base.edit("abXcd", Uuid::from_u128(2));
+        assert_eq!(applied(&base, &[&delete, &insert]).to_string(), "aXd");
+        assert_eq!(applied(&base, &[&insert, &delete]).to_string(), "aXd");
+    }
+
+    #[test]
+    fn applying_a_transition_twice_changes_nothing() {
+        let base = Text::new("hello");
+        let edit = base.edit("yellow", Uuid::from_u128(1));
+        let once = applied(&base, &[&edit]);
+        assert_eq!(once.to_string(), "yellow");
+        assert_eq!(applied(&once, &[&edit]), once);
+    }
+
+    #[test]
+    fn edits_multi_byte_text() {
+        let base = Text::new("héllo wörld");
+        for new in &["héllo, wörld 🎲", "wörld", "", "ü"] {
+            let edit = base.edit(new, Uuid::from_u128(1));
+            assert_eq!(applied(&base, &[&edit]).to_string(), *new);
+        }
+    }
+
+    #[test]
+    fn huge_deletes_are_cheap() {
+        let mut text = Text::new("abc");
+        let first = text.anchor(1).unwrap();
+        text.apply(TextTransition {
+            delete: vec![(first, u32::MAX)],
+            insert: None,
+        });
+        assert_eq!(text.to_string(), "");
+    }
+
+    #[test]
+    fn reads_every_saved_form() {
+        let mut text = Text::new("abc");
+        let edit = text.edit("aXc", Uuid::from_u128(1));
+        text.apply(edit);
+        let spans = serde_json::to_value(&text).unwrap();
+        assert!(spans.is_array());
+        assert_eq!(serde_json::from_value::<Text>(spans).unwrap(), text);
+
+        let plain: Text = serde_json::from_str("\"plain\"").unwrap();
+        assert_eq!(plain.to_string(), "plain");
+
+        let atom = serde_json::to_value(Atom::new("atom".to_string())).unwrap();
+        let atom: Text = serde_json::from_value(atom).unwrap();
+        assert_eq!(atom.to_string(), "atom");
+    }
+}
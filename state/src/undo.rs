@@ -0,0 +1,318 @@
+use crate::player::PlayerKey;
+use crate::text::Text;
+use crate::Character;
+use aper::data_structures::{List, ListOperation, ZenoIndex};
+use aper::StateMachine;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+/// How many steps each player can undo.
+const MAX_STEPS: usize = 20;
+
+/// A character as it was at one point, with where it was and who owned it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Snapshot {
+    pub location: ZenoIndex,
+    pub character: Character,
+    pub owner: Option<PlayerKey>,
+}
+
+/// The characters one transition changed, before and after. `None` means
+/// the character didn't exist.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Step {
+    changes: BTreeMap<Uuid, (Option<Snapshot>, Option<Snapshot>)>,
+}
+
+/// One player's changes to the characters, so that they can be taken back.
+///
+/// Rolls are never undone. A step can only be undone while the characters
+/// it touched are still as it left them, so undoing can't take back
+/// influence spent on a reroll, or somebody else's edits.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct UndoStack {
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+}
+
+pub fn snapshot(
+    characters: &List<Character>,
+    owners: &BTreeMap<Uuid, PlayerKey>,
+) -> BTreeMap<Uuid, Snapshot> {
+    let ids = characters.iter().map(|item| item.id).collect();
+    snapshot_of(characters, owners, &ids)
+}
+
+/// Like [`snapshot`], but only of the characters in `ids` that exist.
+pub fn snapshot_of(
+    characters: &List<Character>,
+    owners: &BTreeMap<Uuid, PlayerKey>,
+    ids: &BTreeSet<Uuid>,
+) -> BTreeMap<Uuid, Snapshot> {
+    characters
+        .iter()
+        .filter(|item| ids.contains(&item.id))
+        .map(|item| {
+            (
+                item.id,
+                Snapshot {
+                    location: item.location.clone(),
+                    character: item.value.clone(),
+                    owner: owners.get(&item.id).copied(),
+                },
+            )
+        })
+        .collect()
+}
+
+impl Step {
+    /// Returns `None` if nothing changed.
+    pub fn between(
+        before: &BTreeMap<Uuid, Snapshot>,
+        after: &BTreeMap<Uuid, Snapshot>,
+    ) -> Option<Self> {
+        let ids: BTreeSet<&Uuid> = before.keys().chain(after.keys()).collect();
+        let changes: BTreeMap<_, _> = ids
+            .into_iter()
+            .filter(|id| before.get(id) != after.get(id))
+            .map(|id| (*id, (before.get(id).cloned(), after.get(id).cloned())))
+            .collect();
+        if changes.is_empty() {
+            None
+        } else {
+            Some(Self { changes })
+        }
+    }
+
+    pub fn characters(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.changes.keys().copied()
+    }
+
+    /// The characters this step touched as they are now.
+    fn now(
+        &self,
+        characters: &List<Character>,
+        owners: &BTreeMap<Uuid, PlayerKey>,
+    ) -> BTreeMap<Uuid, Snapshot> {
+        snapshot_of(characters, owners, &self.characters().collect())
+    }
+
+    /// Makes this step end where `next` does, if both only typed into the
+    /// same text of one character and nothing came in between. Returns
+    /// false if `next` has to be a step of its own.
+    fn absorb(&mut self, next: &Step) -> bool {
+        let (id, (before, after)) = match single(&self.changes) {
+            Some(change) => change,
+            None => return false,
+        };
+        let (next_id, (next_before, next_after)) = match single(&next.changes) {
+            Some(change) => change,
+            None => return false,
+        };
+        let typed = |before: &Option<Snapshot>, after: &Option<Snapshot>| match (before, after) {
+            (Some(before), Some(after)) => typed_into(before, after),
+            _ => None,
+        };
+        if id != next_id || after != next_before {
+            return false;
+        }
+        match (typed(before, after), typed(next_before, next_after)) {
+            (Some(field), Some(next_field)) if field == next_field => {}
+            _ => return false,
+        }
+        self.changes.insert(*id, (before.clone(), next_after.clone()));
+        true
+    }
+
+    /// True if the characters are as this step left them, so that it can be
+    /// undone.
+    fn can_undo(&self, now: &BTreeMap<Uuid, Snapshot>) -> bool {
+        self.changes
+            .iter()
+            .all(|(id, (_, after))| now.get(id) == after.as_ref())
+    }
+
+    fn can_redo(&self, now: &BTreeMap<Uuid, Snapshot>) -> bool {
+        self.changes
+            .iter()
+            .all(|(id, (before, _))| now.get(id) == before.as_ref())
+    }
+
+    /// Puts the characters back as they were on one side of this step. Each
+    /// one is replaced in place, keeping its ID.
+    pub fn put_back(
+        &self,
+        characters: &mut List<Character>,
+        owners: &mut BTreeMap<Uuid, PlayerKey>,
+        undo: bool,
+    ) {
+        for (id, (before, after)) in &self.changes {
+            let (from, to) = if undo { (after, before) } else { (before, after) };
+            if from.is_some() {
+                characters.apply(ListOperation::Delete(*id));
+                owners.remove(id);
+            }
+            if let Some(to) = to {
+                characters.apply(ListOperation::Insert(
+                    to.location.clone(),
+                    *id,
+                    to.character.clone(),
+                ));
+                if let Some(owner) = to.owner {
+                    owners.insert(*id, owner);
+                }
+            }
+        }
+    }
+}
+
+fn single<K, V>(map: &BTreeMap<K, V>) -> Option<(&K, &V)> {
+    match map.len() {
+        1 => map.iter().next(),
+        _ => None,
+    }
+}
+
+/// A piece of text on a character sheet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextField {
+    Name,
+    Habitat,
+    Note(Uuid),
+    Paragraph(Uuid),
+}
+
+/// The text typed into between two versions of a character, if that is
+/// all that changed.
+fn typed_into(before: &Snapshot, after: &Snapshot) -> Option<TextField> {
+    if before.location != after.location || before.owner != after.owner {
+        return None;
+    }
+    let (b, a) = (&before.character, &after.character);
+    let mut rest = b.clone();
+    let field = if b.name != a.name {
+        rest.name = a.name.clone();
+        TextField::Name
+    } else if b.habitat != a.habitat {
+        rest.habitat = a.habitat.clone();
+        TextField::Habitat
+    } else if b.notes != a.notes {
+        rest.notes = a.notes.clone();
+        TextField::Note(item_typed_into(&b.notes, &a.notes)?)
+    } else if b.journal != a.journal {
+        rest.journal = a.journal.clone();
+        TextField::Paragraph(item_typed_into(&b.journal, &a.journal)?)
+    } else {
+        return None;
+    };
+    if rest == *a {
+        Some(field)
+    } else {
+        None
+    }
+}
+
+/// The one item whose text changed, if the items are otherwise the same.
+fn item_typed_into(before: &List<Text>, after: &List<Text>) -> Option<Uuid> {
+    if before.iter().count() != after.iter().count() {
+        return None;
+    }
+    let mut changed = None;
+    for (b, a) in before.iter().zip(after.iter()) {
+        if b.id != a.id || b.location != a.location {
+            return None;
+        }
+        if b.value != a.value {
+            if changed.is_some() {
+                return None;
+            }
+            changed = Some(b.id);
+        }
+    }
+    changed
+}
+
+impl UndoStack {
+    /// Adds a step, or makes the last one include it if both typed into
+    /// the same text, so that undo takes back more than a keystroke.
+    pub fn push(&mut self, step: Step) {
+        self.redo.clear();
+        if let Some(last) = self.undo.last_mut() {
+            if last.absorb(&step) {
+                return;
+            }
+        }
+        self.undo.push(step);
+        if self.undo.len() > MAX_STEPS {
+            self.undo.remove(0);
+        }
+    }
+
+    /// The step [`UndoStack::undo`] would take back, if any.
+    pub fn next_undo(&self) -> Option<&Step> {
+        self.undo.last()
+    }
+
+    pub fn next_redo(&self) -> Option<&Step> {
+        self.redo.last()
+    }
+
+    /// True if the next undo would change anything, rather than only drop a
+    /// step that can no longer be undone.
+    pub fn undo_possible(
+        &self,
+        characters: &List<Character>,
+        owners: &BTreeMap<Uuid, PlayerKey>,
+    ) -> bool {
+        self.undo
+            .last()
+            .map_or(false, |s| s.can_undo(&s.now(characters, owners)))
+    }
+
+    pub fn redo_possible(
+        &self,
+        characters: &List<Character>,
+        owners: &BTreeMap<Uuid, PlayerKey>,
+    ) -> bool {
+        self.redo
+            .last()
+            .map_or(false, |s| s.can_redo(&s.now(characters, owners)))
+    }
+
+    /// Takes back the last step. A step that can no longer be undone is
+    /// dropped instead. Returns true if the characters changed.
+    pub fn undo(
+        &mut self,
+        characters: &mut List<Character>,
+        owners: &mut BTreeMap<Uuid, PlayerKey>,
+    ) -> bool {
+        let step = match self.undo.pop() {
+            Some(step) => step,
+            None => return false,
+        };
+        if !step.can_undo(&step.now(characters, owners)) {
+            return false;
+        }
+        step.put_back(characters, owners, true);
+        self.redo.push(step);
+        true
+    }
+
+    pub fn redo(
+        &mut self,
+        characters: &mut List<Character>,
+        owners: &mut BTreeMap<Uuid, PlayerKey>,
+    ) -> bool {
+        let step = match self.redo.pop() {
+            Some(step) => step,
+            None => return false,
+        };
+        if !step.can_redo(&step.now(characters, owners)) {
+            return false;
+        }
+        step.put_back(characters, owners, false);
+        self.undo.push(step);
+        true
+    }
+}